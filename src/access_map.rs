@@ -0,0 +1,113 @@
+// Tags every byte of memory with the ways it was accessed, to tell code from data when reverse engineering a ROM.
+// Exported like a CDL file, one byte of ACCESS_ flags per address from $0000 to $FFFF.
+
+use std::{fs::File, io::{self, Write}};
+
+pub const ACCESS_OPCODE: u8     = 0b0000_0001;
+pub const ACCESS_OPERAND: u8    = 0b0000_0010;
+pub const ACCESS_READ: u8       = 0b0000_0100;
+pub const ACCESS_WRITE: u8      = 0b0000_1000;
+pub const ACCESS_POINTER: u8    = 0b0001_0000;  // Read as the pointer of an indirect address mode
+
+const ACCESS_NAMES: [(u8, &str); 5] = [(ACCESS_OPCODE, "opcode"), (ACCESS_OPERAND, "operand"), (ACCESS_READ, "read"), (ACCESS_WRITE, "written"), (ACCESS_POINTER, "pointer")];
+
+pub struct AccessMap {
+    pub recording: bool,
+    pub flags: Vec<u8>,
+}
+
+impl AccessMap {
+    pub fn new() -> Self {
+        AccessMap { recording: true, flags: vec![0; 0x10000] }
+    }
+
+    /// Records an instruction of `size` bytes executed at `pc`, with the data it read and wrote
+    pub fn record(&mut self, pc: u16, size: u16, reads: &[u16], pointer_reads: &[u16], writes: &[(u16, u8)]) {
+        self.flags[pc as usize] |= ACCESS_OPCODE;
+        for offset in 1..size {
+            self.flags[pc.wrapping_add(offset) as usize] |= ACCESS_OPERAND;
+        }
+        for address in reads {
+            self.flags[*address as usize] |= ACCESS_READ;
+        }
+        for address in pointer_reads {
+            self.flags[*address as usize] |= ACCESS_POINTER;
+        }
+        for (address, _) in writes {
+            self.flags[*address as usize] |= ACCESS_WRITE;
+        }
+    }
+
+    /// Whether the disassembler should show the byte as data, i.e. it was accessed but never executed as an opcode.
+    /// Operand bytes count as data too, an instruction starting there would not line up with the executed ones.
+    pub fn is_data(&self, address: u16) -> bool {
+        let flags = self.flags[address as usize];
+        flags != 0 && flags & ACCESS_OPCODE == 0
+    }
+
+    /// How many bytes have each kind of access
+    pub fn counts(&self) -> Vec<(&'static str, usize)> {
+        ACCESS_NAMES.iter().map(|(flag, name)| (*name, self.flags.iter().filter(|flags| *flags & flag != 0).count())).collect()
+    }
+
+    pub fn export(&self, filename: &str) -> io::Result<()> {
+        File::create(filename)?.write_all(&self.flags)
+    }
+}
+
+/// The names of the access kinds set in `flags`, e.g. `opcode, written`
+pub fn describe(flags: u8) -> String {
+    let names: Vec<&str> = ACCESS_NAMES.iter().filter(|(flag, _)| flags & flag != 0).map(|(_, name)| *name).collect();
+    if names.is_empty() { "never accessed".to_string() } else { names.join(", ") }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu_runner::CpuRunner;
+    use super::{describe, ACCESS_OPCODE, ACCESS_OPERAND, ACCESS_POINTER, ACCESS_READ, ACCESS_WRITE};
+
+    // 0400 LDA $0300, 0403 STA $0301, 0406 LDA ($10),Y, 0408 JMP ($0012), 040B NOP
+    fn mapped_run() -> CpuRunner {
+        let mut runner = CpuRunner::new();
+        for (address, bytes) in [(0x0400, &[0xAD, 0x00, 0x03, 0x8D, 0x01, 0x03, 0xB1, 0x10, 0x6C, 0x12, 0x00, 0xEA][..]), (0x0010, &[0x02, 0x03, 0x0B, 0x04])] {
+            runner.cpu.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        runner.cpu.pc = 0x0400;
+        runner.execute_command("access on");
+        for _i in 0..5 {
+            runner.step();
+        }
+        assert_eq!(runner.cpu.pc, 0x040C);
+        runner
+    }
+
+    #[test]
+    fn accesses_are_tagged_by_kind() {
+        let runner = mapped_run();
+        let map = runner.access_map.as_ref().unwrap();
+        assert_eq!(map.flags[0x0400..0x0403], [ACCESS_OPCODE, ACCESS_OPERAND, ACCESS_OPERAND]);
+        assert_eq!(map.flags[0x0300..0x0304], [ACCESS_READ, ACCESS_WRITE, ACCESS_READ, 0]);
+        assert_eq!(map.flags[0x0010..0x0014], [ACCESS_POINTER; 4]);
+        assert_eq!(map.flags[0x040B], ACCESS_OPCODE);
+        assert!(map.is_data(0x0300) && map.is_data(0x0401) && !map.is_data(0x0400) && !map.is_data(0x0500));
+        assert_eq!(map.counts(), [("opcode", 5), ("operand", 7), ("read", 2), ("written", 1), ("pointer", 4)]);
+        assert_eq!(describe(ACCESS_OPCODE | ACCESS_WRITE), "opcode, written");
+        assert_eq!(describe(0), "never accessed");
+    }
+
+    #[test]
+    fn export_writes_a_byte_per_address() {
+        let mut runner = mapped_run();
+        let filename = std::env::temp_dir().join(format!("access_map_test_{}.cdl", std::process::id()));
+        runner.execute_command(&format!("access export {}", filename.display()));
+        let exported = std::fs::read(&filename);
+        std::fs::remove_file(&filename).ok();
+        let exported = exported.expect("exported map");
+        assert_eq!(exported.len(), 0x10000);
+        assert_eq!(exported, runner.access_map.as_ref().unwrap().flags);
+        assert_eq!(exported[0x0406..0x0409], [ACCESS_OPCODE, ACCESS_OPERAND, ACCESS_OPCODE]);
+
+        runner.execute_command("access reset");
+        assert!(runner.access_map.as_ref().unwrap().flags.iter().all(|flags| *flags == 0));
+    }
+}
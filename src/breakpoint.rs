@@ -0,0 +1,257 @@
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    Execute,
+    Write,      // Watchpoints stop after the instruction accessing the watched range
+    Read,
+    Access,
+}
+
+#[derive(Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub kind: BreakpointKind,
+    pub address: u16,
+    pub length: u16,        // Amount of bytes watched, always 1 for execution breakpoints
+    pub message: Option<String>,
+    pub enabled: bool,
+    pub temporary: bool,    // Deleted the first time it stops execution
+    pub ignore_count: usize, // How many upcoming hits should not stop execution
+    pub hit_count: usize,
+    pub commands: Vec<String>,  // Debugger commands run when it stops execution
+}
+
+/// A watchpoint that stopped execution, and the access that triggered it
+pub struct WatchHit {
+    pub id: usize,
+    pub address: u16,
+    pub old_value: Option<u8>,  // Only set for writes
+}
+
+/// Numbered breakpoints, in the order they were created
+pub struct BreakpointList {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+}
+
+impl BreakpointList {
+    pub fn new() -> Self {
+        BreakpointList { breakpoints: Vec::new(), next_id: 1 }
+    }
+
+    /// Returns the number assigned to the new breakpoint
+    pub fn add(&mut self, address: u16, message: Option<String>, temporary: bool) -> usize {
+        self.insert(BreakpointKind::Execute, address, 1, message, temporary)
+    }
+
+    pub fn add_watchpoint(&mut self, kind: BreakpointKind, address: u16, length: u16) -> usize {
+        self.insert(kind, address, length.max(1), None, false)
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|bp| bp.id != id);
+        self.breakpoints.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.get_mut(id) {
+            Some(bp) => {
+                bp.enabled = enabled;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn set_ignore_count(&mut self, id: usize, count: usize) -> bool {
+        match self.get_mut(id) {
+            Some(bp) => {
+                bp.ignore_count = count;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn set_commands(&mut self, id: usize, commands: Vec<String>) -> bool {
+        match self.get_mut(id) {
+            Some(bp) => {
+                bp.commands = commands;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Number of the most recently created breakpoint that still exists
+    pub fn last_id(&self) -> Option<usize> {
+        self.breakpoints.last().map(|bp| bp.id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// Whether any data accesses have to be checked
+    pub fn has_watchpoints(&self) -> bool {
+        self.breakpoints.iter().any(|bp| bp.enabled && bp.kind != BreakpointKind::Execute)
+    }
+
+    /// Registers a hit on every enabled breakpoint at `pc`.
+    /// Returns the first breakpoint that should stop execution, temporary breakpoints are removed once they stop.
+    pub fn hit(&mut self, pc: u16) -> Option<Breakpoint> {
+        let index = self.register_hits(|bp| bp.kind == BreakpointKind::Execute && bp.address == pc)?;
+        let bp = self.breakpoints[index].clone();
+        if bp.temporary {
+            self.remove(bp.id);
+        }
+        Some(bp)
+    }
+
+    /// Registers a hit on every enabled watchpoint covering one of the accessed addresses.
+    /// `writes` holds the address and old value of each write, as journaled by the cpu.
+    pub fn hit_watchpoint(&mut self, reads: &[u16], writes: &[(u16, u8)]) -> Option<WatchHit> {
+        let index = self.register_hits(|bp| watched_access(bp, reads, writes).is_some())?;
+        let bp = &self.breakpoints[index];
+        let (address, old_value) = watched_access(bp, reads, writes)?;
+        Some(WatchHit { id: bp.id, address, old_value })
+    }
+
+    fn insert(&mut self, kind: BreakpointKind, address: u16, length: u16, message: Option<String>, temporary: bool) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, kind, address, length, message, enabled: true, temporary, ignore_count: 0, hit_count: 0, commands: Vec::new() });
+        id
+    }
+
+    // Counts a hit on every enabled breakpoint matching `is_hit`, returning the index of the first one that is not ignored
+    fn register_hits<F: FnMut(&Breakpoint) -> bool>(&mut self, mut is_hit: F) -> Option<usize> {
+        let mut stop = None;
+        for (index, bp) in self.breakpoints.iter_mut().enumerate() {
+            if !bp.enabled || !is_hit(bp) {
+                continue;
+            }
+            bp.hit_count += 1;
+            if bp.ignore_count > 0 {
+                bp.ignore_count -= 1;
+            }
+            else if stop.is_none() {
+                stop = Some(index);
+            }
+        }
+        stop
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.iter_mut().find(|bp| bp.id == id)
+    }
+}
+
+// The first access to the range watched by `bp`, as an address and the old value for writes
+fn watched_access(bp: &Breakpoint, reads: &[u16], writes: &[(u16, u8)]) -> Option<(u16, Option<u8>)> {
+    let covers = |address: u16| address.wrapping_sub(bp.address) < bp.length;
+    if matches!(bp.kind, BreakpointKind::Write | BreakpointKind::Access) {
+        if let Some((address, old_value)) = writes.iter().find(|(address, _)| covers(*address)) {
+            return Some((*address, Some(*old_value)));
+        }
+    }
+    if matches!(bp.kind, BreakpointKind::Read | BreakpointKind::Access) {
+        if let Some(address) = reads.iter().find(|address| covers(**address)) {
+            return Some((*address, None));
+        }
+    }
+    None
+}
+
+impl BreakpointKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BreakpointKind::Execute => "breakpoint",
+            BreakpointKind::Write => "write watchpoint",
+            BreakpointKind::Read => "read watchpoint",
+            BreakpointKind::Access => "access watchpoint",
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<4} {:<5} {:<4} {:04X}    {:<5}", self.id, if self.temporary {"del"} else {"keep"}, if self.enabled {"y"} else {"n"}, self.address, self.hit_count)?;
+        if self.kind != BreakpointKind::Execute {
+            write!(f, " {} ({} bytes)", self.kind.name(), self.length)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, " {}", message)?;
+        }
+        if self.ignore_count > 0 {
+            write!(f, " (ignore next {} hits)", self.ignore_count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BreakpointList;
+
+    #[test]
+    fn numbers_are_never_reused() {
+        let mut list = BreakpointList::new();
+        assert_eq!(list.add(0x0400, None, false), 1);
+        assert_eq!(list.add(0x0410, None, false), 2);
+        assert!(list.remove(2));
+        assert!(!list.remove(2));
+        assert_eq!(list.add(0x0420, None, false), 3);
+        list.clear();
+        assert!(list.is_empty());
+        assert_eq!(list.add(0x0430, None, false), 4);
+    }
+
+    #[test]
+    fn hits_are_counted_on_every_enabled_breakpoint() {
+        let mut list = BreakpointList::new();
+        let first = list.add(0x0400, Some("first".to_string()), false);
+        let second = list.add(0x0400, None, false);
+        let stop = list.hit(0x0400).expect("stops at 0400");
+        assert_eq!((stop.id, stop.message.as_deref()), (first, Some("first")));
+        assert!(list.hit(0x0401).is_none());
+        assert!(list.set_enabled(first, false));
+        assert_eq!(list.hit(0x0400).map(|bp| bp.id), Some(second));
+        let counts: Vec<usize> = list.iter().map(|bp| bp.hit_count).collect();
+        assert_eq!(counts, [1, 2]);
+        assert!(!list.set_enabled(9, false));
+    }
+
+    #[test]
+    fn ignored_hits_count_without_stopping() {
+        let mut list = BreakpointList::new();
+        let id = list.add(0x0400, None, false);
+        assert!(list.set_ignore_count(id, 2));
+        assert!(list.hit(0x0400).is_none());
+        assert!(list.hit(0x0400).is_none());
+        assert!(list.hit(0x0400).is_some());
+        assert_eq!(list.iter().next().map(|bp| (bp.hit_count, bp.ignore_count)), Some((3, 0)));
+        assert!(!list.set_ignore_count(9, 1));
+    }
+
+    #[test]
+    fn temporary_breakpoints_are_removed_once_they_stop() {
+        let mut list = BreakpointList::new();
+        let id = list.add(0x0400, None, true);
+        list.set_ignore_count(id, 1);
+        assert!(list.hit(0x0400).is_none());
+        assert_eq!(list.iter().count(), 1);
+        assert_eq!(list.hit(0x0400).map(|bp| bp.id), Some(id));
+        assert!(list.is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::{cpu::Cpu, cpu_helpers::{Instruction, CpuState, Operation}};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Subroutine,
+    Interrupt,
+}
+
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub caller_pc: u16,         // Address of the JSR or of the interrupted instruction
+    pub target: u16,            // Entry point of the routine
+    pub sp_at_entry: u8,        // Stack pointer after the return address was pushed
+    pub return_address: u16,    // Where execution continues once the frame returns
+}
+
+/// What a single instruction did to the shadow stack, so that it can be undone when stepping back
+#[derive(Clone, Default)]
+pub struct CallStackChange {
+    pub pushed: bool,
+    pub popped: Vec<Frame>,
+}
+
+/// Shadow call stack built by following JSR/RTS and interrupt entry/RTI
+pub struct CallStack {
+    pub frames: Vec<Frame>,
+    pub desync_count: usize,            // How often the real stack stopped matching the shadow stack
+    pub last_desync: Option<String>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack { frames: Vec::new(), desync_count: 0, last_desync: None }
+    }
+
+    /// Updates the shadow stack after `instruction` was executed from the state `before`.
+    /// Returns a warning if the instruction broke the shadow stack.
+    pub fn update(&mut self, before: &CpuState, instruction: &Instruction, cpu: &Cpu, change: &mut CallStackChange) -> Option<String> {
+        change.pushed = false;
+        change.popped.clear();
+        match instruction.operation {
+            Operation::Jsr => {
+                self.push(Frame { kind: FrameKind::Subroutine, caller_pc: before.pc, target: cpu.pc, sp_at_entry: cpu.sp, return_address: before.pc.wrapping_add(3) }, change);
+                None
+            },
+            Operation::Brk => {
+                // BRK skips a padding byte, so the return address is two bytes after the opcode
+                self.push(Frame { kind: FrameKind::Interrupt, caller_pc: before.pc, target: cpu.pc, sp_at_entry: cpu.sp, return_address: before.pc.wrapping_add(2) }, change);
+                None
+            },
+            Operation::Rts => self.pop(FrameKind::Subroutine, before, cpu, change),
+            Operation::Rti => self.pop(FrameKind::Interrupt, before, cpu, change),
+            Operation::Txs => {
+                // Frames whose return address now lies above the stack pointer can never be returned to
+                let mut discarded = 0;
+                while let Some(frame) = self.frames.last() {
+                    if frame.sp_at_entry >= cpu.sp {
+                        break;
+                    }
+                    change.popped.push(self.frames.pop().unwrap());
+                    discarded += 1;
+                }
+                if discarded > 0 {
+                    return self.desync(format!("TXS at {:04X} discarded {} frame(s)", before.pc, discarded));
+                }
+                None
+            },
+            _ => None,
+        }
+    }
+
+    /// Reverts a change previously returned by `update`
+    pub fn undo(&mut self, change: &CallStackChange) {
+        if change.pushed {
+            self.frames.pop();
+        }
+        for frame in change.popped.iter().rev() {
+            self.frames.push(*frame);
+        }
+    }
+
+    fn push(&mut self, frame: Frame, change: &mut CallStackChange) {
+        self.frames.push(frame);
+        change.pushed = true;
+    }
+
+    fn pop(&mut self, kind: FrameKind, before: &CpuState, cpu: &Cpu, change: &mut CallStackChange) -> Option<String> {
+        let name = if kind == FrameKind::Subroutine {"RTS"} else {"RTI"};
+        // Something was pushed on top of the return address, e.g. pushing an address and using RTS as a jump
+        let top_sp = match self.frames.last() {
+            Some(frame) => frame.sp_at_entry,
+            None => return self.desync(format!("{} at {:04X} to {:04X} without a matching call", name, before.pc, cpu.pc)),
+        };
+        if before.sp < top_sp {
+            return self.desync(format!("{} at {:04X} to {:04X} was not pushed by a call", name, before.pc, cpu.pc));
+        }
+
+        // Frames below the stack pointer were dropped without returning, e.g. PLA PLA RTS
+        while let Some(frame) = self.frames.last() {
+            if frame.sp_at_entry >= before.sp {
+                break;
+            }
+            change.popped.push(self.frames.pop().unwrap());
+        }
+        let dropped = change.popped.len();
+
+        let frame = match self.frames.last() {
+            Some(frame) if frame.sp_at_entry == before.sp => *frame,
+            _ => return self.desync(format!("{} at {:04X} did not match any frame, {} frame(s) dropped", name, before.pc, dropped)),
+        };
+        change.popped.push(self.frames.pop().unwrap());
+
+        if frame.kind != kind {
+            self.desync(format!("{} at {:04X} returned from a frame entered by {}", name, before.pc, if frame.kind == FrameKind::Subroutine {"JSR"} else {"an interrupt"}))
+        }
+        else if frame.return_address != cpu.pc {
+            self.desync(format!("{} at {:04X} returned to {:04X} instead of {:04X}", name, before.pc, cpu.pc, frame.return_address))
+        }
+        else if dropped > 0 {
+            self.desync(format!("{} at {:04X} dropped {} frame(s) that never returned", name, before.pc, dropped))
+        }
+        else {
+            None
+        }
+    }
+
+    fn desync(&mut self, message: String) -> Option<String> {
+        self.desync_count += 1;
+        self.last_desync = Some(message.clone());
+        Some(message)
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<5} {:04X}     {:04X}    {:02X}   {:04X}", if self.kind == FrameKind::Subroutine {"JSR"} else {"IRQ"}, self.target, self.caller_pc, self.sp_at_entry, self.return_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallStack, CallStackChange, FrameKind};
+    use crate::cpu::Cpu;
+
+    fn cpu_with(program: &[(usize, &[u8])]) -> Cpu {
+        let mut cpu = Cpu::new();
+        for (address, bytes) in program {
+            cpu.memory[*address..*address + bytes.len()].copy_from_slice(bytes);
+        }
+        cpu.pc = 0x0400;
+        cpu
+    }
+
+    // Executes `count` instructions, returning the warnings of the last one and its change
+    fn run(cpu: &mut Cpu, stack: &mut CallStack, count: usize) -> (Option<String>, CallStackChange) {
+        let mut result = (None, CallStackChange::default());
+        for _i in 0..count {
+            let before = cpu.get_cpu_state();
+            let instruction = cpu.get_next_instruction();
+            cpu.execute_next_instruction();
+            result.0 = stack.update(&before, &instruction, cpu, &mut result.1);
+        }
+        result
+    }
+
+    #[test]
+    fn calls_and_returns_keep_it_in_sync() {
+        // 0400 JSR $0410, 0410 JSR $0420, 0420 RTS, 0413 RTS
+        let mut cpu = cpu_with(&[(0x0400, &[0x20, 0x10, 0x04]), (0x0410, &[0x20, 0x20, 0x04, 0x60]), (0x0420, &[0x60])]);
+        let mut stack = CallStack::new();
+        run(&mut cpu, &mut stack, 2);
+        let frames: Vec<(u16, u16, u8)> = stack.frames.iter().map(|frame| (frame.target, frame.return_address, frame.sp_at_entry)).collect();
+        assert_eq!(frames, [(0x0410, 0x0403, 0xFD), (0x0420, 0x0413, 0xFB)]);
+        assert!(run(&mut cpu, &mut stack, 2).0.is_none());
+        assert!(stack.frames.is_empty());
+        assert_eq!((stack.desync_count, cpu.pc), (0, 0x0403));
+    }
+
+    #[test]
+    fn brk_enters_an_interrupt_frame() {
+        let mut cpu = cpu_with(&[(0x0400, &[0x00]), (0xFFFE, &[0x00, 0x05]), (0x0500, &[0x40])]);
+        let mut stack = CallStack::new();
+        run(&mut cpu, &mut stack, 1);
+        assert!(stack.frames.last().is_some_and(|frame| frame.kind == FrameKind::Interrupt && frame.return_address == 0x0402));
+        assert!(run(&mut cpu, &mut stack, 1).0.is_none());
+        assert!(stack.frames.is_empty());
+    }
+
+    #[test]
+    fn returns_no_call_pushed_are_desyncs() {
+        // RTS used as a jump: 0400 LDA #$04, PHA, LDA #$0F, PHA, RTS to $0410
+        let mut cpu = cpu_with(&[(0x0400, &[0xA9, 0x04, 0x48, 0xA9, 0x0F, 0x48, 0x60])]);
+        let mut stack = CallStack::new();
+        let warning = run(&mut cpu, &mut stack, 5).0.expect("desync");
+        assert!(warning.contains("without a matching call"), "{}", warning);
+
+        // The same trick inside a routine: 0400 JSR $0410, 0410 LDA #$04, PHA, LDA #$1F, PHA, RTS
+        let mut cpu = cpu_with(&[(0x0400, &[0x20, 0x10, 0x04]), (0x0410, &[0xA9, 0x04, 0x48, 0xA9, 0x1F, 0x48, 0x60])]);
+        let mut stack = CallStack::new();
+        let warning = run(&mut cpu, &mut stack, 6).0.expect("desync");
+        assert!(warning.contains("was not pushed by a call"), "{}", warning);
+        assert_eq!((stack.frames.len(), stack.desync_count), (1, 1));
+        assert_eq!(stack.last_desync.as_deref(), Some(warning.as_str()));
+    }
+
+    #[test]
+    fn frames_pulled_off_the_stack_are_dropped() {
+        // 0400 JSR $0410, 0410 JSR $0420, 0420 PLA PLA RTS returns straight to 0403
+        let mut cpu = cpu_with(&[(0x0400, &[0x20, 0x10, 0x04]), (0x0410, &[0x20, 0x20, 0x04]), (0x0420, &[0x68, 0x68, 0x60])]);
+        let mut stack = CallStack::new();
+        let (warning, change) = run(&mut cpu, &mut stack, 5);
+        assert!(warning.expect("desync").contains("dropped 1 frame(s)"));
+        assert_eq!((cpu.pc, stack.frames.len()), (0x0403, 0));
+        stack.undo(&change);
+        assert_eq!(stack.frames.iter().map(|frame| frame.target).collect::<Vec<_>>(), [0x0410, 0x0420]);
+    }
+
+    #[test]
+    fn txs_discards_frames_above_the_new_stack_pointer() {
+        // 0400 JSR $0410, 0410 LDX #$FF, TXS
+        let mut cpu = cpu_with(&[(0x0400, &[0x20, 0x10, 0x04]), (0x0410, &[0xA2, 0xFF, 0x9A])]);
+        let mut stack = CallStack::new();
+        let (warning, change) = run(&mut cpu, &mut stack, 3);
+        assert!(warning.expect("desync").contains("discarded 1 frame(s)"));
+        assert!(stack.frames.is_empty());
+        stack.undo(&change);
+        assert_eq!(stack.frames.len(), 1);
+    }
+}
@@ -0,0 +1,43 @@
+// Narrows down where a program keeps a value, e.g. a game's lives or score.
+// Take a snapshot, let the program run, then keep only the addresses whose value changed the expected way.
+
+pub enum CheatFilter {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    Equal(u8),
+    NotEqual(u8),
+}
+
+pub struct CheatFinder {
+    pub candidates: Vec<u16>,
+    snapshot: Vec<u8>,  // Memory as of the last snapshot or narrowing step
+}
+
+impl CheatFinder {
+    /// Every address in the inclusive range starts out as a candidate
+    pub fn new(memory: &[u8], start: u16, end: u16) -> Self {
+        CheatFinder { candidates: (start..=end).collect(), snapshot: memory.to_vec() }
+    }
+
+    /// Keeps the candidates matching the filter, comparing against the previous snapshot, and takes a new one.
+    /// Returns how many candidates are left.
+    pub fn narrow(&mut self, memory: &[u8], filter: &CheatFilter) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|address| {
+            let old = snapshot[*address as usize];
+            let new = memory[*address as usize];
+            match filter {
+                CheatFilter::Changed => new != old,
+                CheatFilter::Unchanged => new == old,
+                CheatFilter::Increased => new > old,
+                CheatFilter::Decreased => new < old,
+                CheatFilter::Equal(value) => new == *value,
+                CheatFilter::NotEqual(value) => new != *value,
+            }
+        });
+        self.snapshot.copy_from_slice(memory);
+        self.candidates.len()
+    }
+}
@@ -0,0 +1,168 @@
+// Records which addresses were executed as instruction starts, and which way every conditional branch went.
+// Exported as a per-address report, or as an lcov tracefile against the source lines of a debug file, which genhtml turns into HTML.
+
+use std::{collections::{BTreeMap, HashMap}, io::{self, Write}};
+
+use crate::{cpu::Cpu, cpu_helpers::{Instruction, AddressMode}, debug_info::DebugInfo};
+
+#[derive(Clone, Copy, Default)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl BranchCoverage {
+    pub fn name(&self) -> &'static str {
+        match (self.taken > 0, self.not_taken > 0) {
+            (true, true) => "both",
+            (true, false) => "taken only",
+            (false, true) => "not taken only",
+            (false, false) => "never",
+        }
+    }
+}
+
+pub struct Coverage {
+    pub recording: bool,
+    pub executions: Vec<u64>,   // Per address, counting instruction starts
+    pub branches: BTreeMap<u16, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage { recording: true, executions: vec![0; 0x10000], branches: BTreeMap::new() }
+    }
+
+    /// Records `instruction` executed at `pc`, after which execution continued at `next_pc`
+    pub fn record(&mut self, pc: u16, instruction: &Instruction, next_pc: u16) {
+        self.executions[pc as usize] += 1;
+        // Relative addressing is only used by the conditional branches.
+        // A branch to the next instruction cannot be told apart from one not taken.
+        if instruction.address_mode == AddressMode::Rel {
+            let branch = self.branches.entry(pc).or_default();
+            if next_pc == pc.wrapping_add(2) {
+                branch.not_taken += 1;
+            }
+            else {
+                branch.taken += 1;
+            }
+        }
+    }
+
+    pub fn executed_addresses(&self) -> usize {
+        self.executions.iter().filter(|count| **count > 0).count()
+    }
+
+    /// Writes one line per executed address, e.g. `3          0410 DEY`, with the direction of branches.
+    /// `disassemble` formats the instruction at an address.
+    pub fn write_report(&self, writer: &mut impl Write, disassemble: impl Fn(u16) -> String) -> io::Result<()> {
+        for (address, count) in self.executions.iter().enumerate().filter(|(_, count)| **count > 0) {
+            let address = address as u16;
+            match self.branches.get(&address) {
+                Some(branch) => writeln!(writer, "{:<10} {:<40} branch {}, taken {}, not taken {}", count, disassemble(address), branch.name(), branch.taken, branch.not_taken)?,
+                None => writeln!(writer, "{:<10} {}", count, disassemble(address))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an lcov tracefile with a record per source file of `debug_info`.
+    /// A line counts as executed as often as the most executed instruction it generated,
+    /// and the branches on it are decoded from `cpu` so that branches never reached show up too.
+    pub fn write_lcov(&self, writer: &mut impl Write, debug_info: &DebugInfo, cpu: &Cpu) -> io::Result<()> {
+        let mut files: HashMap<usize, Vec<(usize, &[u16])>> = HashMap::new();
+        for (location, addresses) in debug_info.lines() {
+            files.entry(location.file).or_default().push((location.line, addresses));
+        }
+        let mut file_ids: Vec<usize> = files.keys().copied().collect();
+        file_ids.sort_unstable();
+
+        writeln!(writer, "TN:")?;
+        for file in file_ids.iter() {
+            let lines = files.get_mut(file).expect("collected above");
+            lines.sort_unstable_by_key(|(line, _)| *line);
+            let source = &debug_info.files[file];
+            let path = source.path.as_ref().map(|path| path.display().to_string()).unwrap_or_else(|| source.name.clone());
+            writeln!(writer, "SF:{}", path)?;
+
+            let (mut lines_hit, mut branches_found, mut branches_hit) = (0, 0, 0);
+            for (line, addresses) in lines.iter() {
+                let count = addresses.iter().map(|address| self.executions[*address as usize]).max().unwrap_or(0);
+                if count > 0 {
+                    lines_hit += 1;
+                }
+                writeln!(writer, "DA:{},{}", line, count)?;
+
+                for (block, address) in addresses.iter().enumerate() {
+                    // The operand of an instruction at the very end of memory would wrap around
+                    if *address > 0xFFFD || cpu.get_instruction_at(*address).address_mode != AddressMode::Rel {
+                        continue;
+                    }
+                    // lcov wants - for the branches of a line that never ran
+                    let (taken, not_taken) = match self.branches.get(address) {
+                        Some(branch) => (branch.taken.to_string(), branch.not_taken.to_string()),
+                        None => ("-".to_string(), "-".to_string()),
+                    };
+                    writeln!(writer, "BRDA:{},{},0,{}", line, block, taken)?;
+                    writeln!(writer, "BRDA:{},{},1,{}", line, block, not_taken)?;
+                    branches_found += 2;
+                    branches_hit += [&taken, &not_taken].iter().filter(|count| !matches!(count.as_str(), "-" | "0")).count();
+                }
+            }
+            writeln!(writer, "BRF:{}", branches_found)?;
+            writeln!(writer, "BRH:{}", branches_hit)?;
+            writeln!(writer, "LF:{}", lines.len())?;
+            writeln!(writer, "LH:{}", lines_hit)?;
+            writeln!(writer, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{write, remove_file};
+
+    use super::Coverage;
+    use crate::{cpu::Cpu, debug_info::DebugInfo};
+
+    // Three lines of one source file: LDX #$00 at $0400, BNE at $0402 and a BEQ at $0404 that never runs
+    const DEBUG_FILE: &str = "version\tmajor=2,minor=0
+file\tid=0,name=\"coverage_test_source.s\",size=0,mtime=0x0,mod=0
+seg\tid=0,name=\"CODE\",start=0x000400,size=0x0006,addrsize=absolute,type=ro
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=4,size=2
+line\tid=0,file=0,line=1,span=0
+line\tid=1,file=0,line=2,span=1
+line\tid=2,file=0,line=3,span=2
+";
+
+    #[test]
+    fn lcov_counts_lines_and_branch_directions() {
+        let filename = std::env::temp_dir().join(format!("coverage_test_{}.dbg", std::process::id()));
+        write(&filename, DEBUG_FILE).expect("temporary debug file");
+        let debug_info = DebugInfo::load_file(filename.to_str().unwrap());
+        remove_file(&filename).ok();
+        let debug_info = debug_info.unwrap_or_else(|err| panic!("{}", err));
+
+        let mut cpu = Cpu::new();
+        cpu.memory[0x0400..0x0406].copy_from_slice(&[0xA2, 0x00, 0xD0, 0xFC, 0xF0, 0x00]);
+        let mut coverage = Coverage::new();
+        for next_pc in [0x0400, 0x0400, 0x0404] {
+            coverage.record(0x0400, &cpu.get_instruction_at(0x0400), 0x0402);
+            coverage.record(0x0402, &cpu.get_instruction_at(0x0402), next_pc);
+        }
+
+        let mut output = Vec::new();
+        coverage.write_lcov(&mut output, &debug_info, &cpu).unwrap();
+        let expected = [
+            "TN:", "SF:coverage_test_source.s",
+            "DA:1,3",
+            "DA:2,3", "BRDA:2,0,0,2", "BRDA:2,0,1,1",
+            "DA:3,0", "BRDA:3,0,0,-", "BRDA:3,0,1,-",
+            "BRF:4", "BRH:2", "LF:3", "LH:2", "end_of_record",
+        ];
+        assert_eq!(String::from_utf8(output).unwrap().lines().collect::<Vec<_>>(), expected);
+    }
+}
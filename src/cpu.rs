@@ -1,5 +1,5 @@
 use byteorder::{ByteOrder, LittleEndian};
-use crate::{bcd::BcdOps, cpu_helpers::{Instruction, AddressMode, Operand, FLAG_CARRY, FLAG_DECIMAL, FLAG_OVERFLOW, FLAG_NEGATIVE, FLAG_ZERO, STACK_START, FLAG_INTERRUPT, IRQ_VECTOR, FLAG_BREAK, FLAG_UNUSED, OP_CODE_MAP, ADDRESS_MODE_MAP, CpuState, CycleType}};
+use crate::{bcd::BcdOps, cpu_helpers::{Instruction, AddressMode, Operand, FLAG_CARRY, FLAG_DECIMAL, FLAG_OVERFLOW, FLAG_NEGATIVE, FLAG_ZERO, STACK_START, FLAG_INTERRUPT, IRQ_VECTOR, FLAG_BREAK, FLAG_UNUSED, OP_CODE_MAP, ADDRESS_MODE_MAP, CpuState, CycleType, Operation}};
 
 #[macro_export]
 macro_rules! stack_index {
@@ -17,7 +17,11 @@ pub struct Cpu {
     pub sr: u8,   // Status register
 
     pub memory: [u8; 0x10000],
-    pub cycles: u64
+    pub cycles: u64,
+
+    pub write_journal: Vec<(u16, u8)>, // (address, old value) of every write since the journal was last emptied
+    pub journal_writes: bool,           // Off when nothing looks at the journal
+    pub memory_changes: u64,            // Writes that changed a value, counted with or without the journal
 }
 
 impl Cpu {
@@ -31,18 +35,29 @@ impl Cpu {
             sr: 0b0011_0100,
 
             memory: [0; 0x10000],
-            cycles: 0
+            cycles: 0,
+
+            write_journal: Vec::new(),
+            journal_writes: true,
+            memory_changes: 0,
         }
     }
 
     pub fn write_memory_u8(&mut self, ind: u16, val: u8) {
+        let old_value = self.memory[ind as usize];
+        if self.journal_writes {
+            self.write_journal.push((ind, old_value));
+        }
+        if old_value != val {
+            self.memory_changes += 1;
+        }
         self.memory[ind as usize] = val;
     }
 
+    /// Writes the high byte at `ind` and the low byte below it, the order in which they are pushed to the stack
     pub fn write_memory_u16(&mut self, ind: u16, val: u16) {
-        self.memory[ind as usize] = ((val & 0xFF00) >> 4) as u8;
-        let buf = &mut self.memory[(ind - 1) as usize ..= ind as usize];
-        LittleEndian::write_u16(buf, val);
+        self.write_memory_u8(ind, (val >> 8) as u8);
+        self.write_memory_u8(ind - 1, (val & 0xFF) as u8);
     }
 
     pub fn read_memory_u8(&self, ind: u16) -> u8{
@@ -60,7 +75,8 @@ impl Cpu {
             LittleEndian::read_u16(&bytes)
         }
         else{
-            let bytes = &self.memory[uind..=uind+1];
+            // Wraps around at the end of memory, like the address bus does
+            let bytes = [self.memory[uind], self.memory[ind.wrapping_add(1) as usize]];
             LittleEndian::read_u16(&bytes)
         }
     }
@@ -82,6 +98,16 @@ impl Cpu {
         CpuState{a: self.a, x: self.x, y: self.y, pc: self.pc, sp: self.sp, sr: self.sr, cycles: self.cycles}
     }
 
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        self.a = state.a;
+        self.x = state.x;
+        self.y = state.y;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.sr = state.sr;
+        self.cycles = state.cycles;
+    }
+
     pub fn get_next_instruction(&self) -> Instruction {
         self.get_instruction_at(self.pc)
     }
@@ -93,10 +119,10 @@ impl Cpu {
         let value: u16 ;
         let address_size = mode.address_size();
         if address_size == 2 {
-            value = self.read_memory_u16(self.pc + 1);
+            value = self.read_memory_u16(pos.wrapping_add(1));
         }
         else if address_size == 1 {
-            value = self.read_memory_u8(self.pc + 1) as u16;
+            value = self.read_memory_u8(pos.wrapping_add(1)) as u16;
         }
         else {
             value = 0;
@@ -104,6 +130,58 @@ impl Cpu {
         Instruction { operation: op, address_mode: mode, value: value, cycles: 0 }
     }
 
+    /// The memory address the instruction at `pos` operates on, or None if it does not address memory.
+    /// Calculated like get_operand, but without executing anything.
+    pub fn get_effective_address(&self, pos: u16) -> Option<u16> {
+        let mode = ADDRESS_MODE_MAP[self.memory[pos as usize] as usize];
+        let operand = pos.wrapping_add(1);
+        match mode {
+            AddressMode::Abs => Some(self.read_memory_u16(operand)),
+            AddressMode::Abx => Some(self.read_memory_u16(operand).wrapping_add(self.x as u16)),
+            AddressMode::Aby => Some(self.read_memory_u16(operand).wrapping_add(self.y as u16)),
+            AddressMode::Ind => Some(self.read_memory_u16(self.read_memory_u16(operand))),
+            AddressMode::Inx => Some(self.read_memory_u16(((self.read_memory_u8(operand) as u16) + (self.x as u16))&0xFF)),
+            AddressMode::Iny => Some(self.read_memory_u16(self.read_memory_u8(operand) as u16).wrapping_add(self.y as u16)),
+            AddressMode::Zpg => Some(self.read_memory_u8(operand) as u16),
+            AddressMode::Zpx => Some(self.read_memory_u8(operand).wrapping_add(self.x) as u16),
+            AddressMode::Zpy => Some(self.read_memory_u8(operand).wrapping_add(self.y) as u16),
+            _ => None,
+        }
+    }
+
+    /// Adds the addresses of the data the next instruction reads, i.e. its operand's target and pulled stack bytes
+    pub fn get_next_data_reads(&self, reads: &mut Vec<u16>) {
+        let operation = OP_CODE_MAP[self.memory[self.pc as usize] as usize];
+        let pulled = match operation {
+            Operation::Pla | Operation::Plp => 1,
+            Operation::Rts => 2,
+            Operation::Rti => 3,
+            _ => 0,
+        };
+        for offset in 1..=pulled {
+            reads.push(stack_index!(self.sp.wrapping_add(offset)));
+        }
+        if operation.reads_operand() {
+            if let Some(address) = self.get_effective_address(self.pc) {
+                reads.push(address);
+            }
+        }
+    }
+
+    /// Adds the addresses of the pointer the next instruction reads through, for the indirect address modes
+    pub fn get_next_pointer_reads(&self, reads: &mut Vec<u16>) {
+        let mode = ADDRESS_MODE_MAP[self.memory[self.pc as usize] as usize];
+        let operand = self.pc.wrapping_add(1);
+        let pointer = match mode {
+            AddressMode::Ind => self.read_memory_u16(operand),
+            AddressMode::Inx => ((self.read_memory_u8(operand) as u16) + (self.x as u16))&0xFF,
+            AddressMode::Iny => self.read_memory_u8(operand) as u16,
+            _ => return,
+        };
+        reads.push(pointer);
+        reads.push(pointer.wrapping_add(1));
+    }
+
     /// Execute the instruction specified via the program counter.
     /// Returns the clock cycles required for this instruction.
     /// See https://www.masswerk.at/6502/6502_instruction_set.html#ADC
@@ -240,6 +240,14 @@ impl AddressMode {
     }
 }
 
+impl Operation {
+    /// Whether the operation reads the memory its operand points to. Stores only write it.
+    pub fn reads_operand(&self) -> bool {
+        matches!(self, Operation::Adc | Operation::And | Operation::Asl | Operation::Bit | Operation::Cmp | Operation::Cpx | Operation::Cpy | Operation::Dec
+            | Operation::Eor | Operation::Inc | Operation::Lda | Operation::Ldx | Operation::Ldy | Operation::Lsr | Operation::Ora | Operation::Rol | Operation::Ror | Operation::Sbc)
+    }
+}
+
 impl Instruction {
     pub fn new() -> Self {
         Instruction{ operation: Operation::Inv, address_mode: AddressMode::Inv, value: 0, cycles: 0 }
@@ -309,6 +317,11 @@ impl fmt::Display for Instruction {
 }
 impl fmt::Display for CpuState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "PC: {:04X}, A: {:02X}, X: {:02X}, Y: {:02X}, SP: {:02X}, SR: {:02X}, NV-BDIZC: {:08b}, cycles: {}", self.pc, self.a, self.x, self.y, self.sp, self.sr, self.sr, self.cycles)
+        write!(f, "PC: {:04X}, A: {:02X}, X: {:02X}, Y: {:02X}, SP: {:02X}, {}, cycles: {}", self.pc, self.a, self.x, self.y, self.sp, format_status(self.sr), self.cycles)
     }
+}
+
+/// The status register in hex and as its NV-BDIZC bits
+pub fn format_status(sr: u8) -> String {
+    format!("SR: {:02X}, NV-BDIZC: {:08b}", sr, sr)
 }
\ No newline at end of file
@@ -1,60 +1,410 @@
-use std::{io::{stdin, Write}, collections::{HashMap}, fs::File};
+use std::{io::{stdin, Write, BufWriter}, fs::{File, read, read_to_string}, collections::{HashMap, VecDeque}, sync::{Arc, atomic::{AtomicBool, Ordering}}};
 
-use crate::{cpu::Cpu, cpu_helpers::{Instruction, CpuState}};
+use strum_macros::EnumIter;
 
-const HISTORY_SIZE: usize = 1000; 
+use crate::{cpu::Cpu, cpu_helpers::{Instruction, CpuState, Operation, FLAG_CARRY, FLAG_ZERO, FLAG_INTERRUPT, FLAG_DECIMAL, FLAG_BREAK, FLAG_OVERFLOW, FLAG_NEGATIVE, format_status}, breakpoint::{BreakpointList, BreakpointKind, WatchHit}, call_stack::{CallStack, CallStackChange}, symbols::SymbolTable, disassembler::{format_instruction, format_data}, debug_info::{DebugInfo, SourceLocation}, cheat_finder::{CheatFinder, CheatFilter}, trace::{TraceFormat, nestest_line, print_trace_diff}, loop_detector::{LoopDetector, LoopHit, TrapKind}, line_editor::LineEditor, expression::{evaluate, ExpressionError}, memory_view::MemoryFormat, stack_inspector::{inspect_stack, StackEntryKind}, profiler::Profiler, coverage::Coverage, access_map::{AccessMap, describe}, smc_detector::{SmcDetector, SmcHit, SmcMode}, stack_checker::{StackChecker, StackCheckMode, StackIssue, StackIssueKind}};
+
+const DEFAULT_HISTORY_DEPTH: usize = 1000;
+const MAX_LISTED_ADDRESSES: usize = 32;    // Longer results of find and cheat only show their count
+const FOLLOWED_POINTER_SIZE: usize = 0x40;  // Bytes mem shows when only given a pointer to follow
+const DEFAULT_PROFILE_ROWS: usize = 20;
+const DEFAULT_DISASSEMBLY_LINES: usize = 16;
+const DATA_BYTES_PER_LINE: usize = 8;
+const MAX_MACRO_DEPTH: usize = 1024;    // Macros calling themselves, directly or through others, stop here
+
+// Every command the prompt understands. Aliases map to the same command, and tab completion offers all the names
+#[derive(Clone, Copy, PartialEq, Debug, EnumIter)]
+enum Command {
+    MemDec, Mem, Reg, Op, Hist, History, Trace, TraceDiff, Backtrace, Stack,
+    Symbols, DebugInfo, List, StepLine, NextLine, Dump, Load, Set, Poke, Fill,
+    Copy, Find, Cheat, Profile, Coverage, Access, Disasm, Smc, Break, TBreak,
+    Watch, RWatch, AWatch, Trap, Loop, Info, Delete, Enable, Disable, Ignore,
+    Continue, Step, Over, Finish, Until, Back, ReverseContinue, Exit, Source, Define,
+    Commands,
+}
+
+const COMMANDS: &[(&str, Command)] = &[
+    ("mem_dec", Command::MemDec),
+    ("mem", Command::Mem),
+    ("reg", Command::Reg),
+    ("op", Command::Op),
+    ("hist", Command::Hist),
+    ("history", Command::History),
+    ("trace", Command::Trace),
+    ("tracediff", Command::TraceDiff),
+    ("bt", Command::Backtrace), ("backtrace", Command::Backtrace),
+    ("stack", Command::Stack),
+    ("symbols", Command::Symbols), ("sym", Command::Symbols),
+    ("dbginfo", Command::DebugInfo), ("dbg", Command::DebugInfo),
+    ("list", Command::List), ("l", Command::List),
+    ("step-line", Command::StepLine), ("sl", Command::StepLine),
+    ("next-line", Command::NextLine), ("nl", Command::NextLine),
+    ("dump", Command::Dump),
+    ("load", Command::Load),
+    ("set", Command::Set),
+    ("poke", Command::Poke),
+    ("fill", Command::Fill),
+    ("copy", Command::Copy),
+    ("find", Command::Find),
+    ("cheat", Command::Cheat),
+    ("profile", Command::Profile),
+    ("coverage", Command::Coverage),
+    ("access", Command::Access),
+    ("disasm", Command::Disasm),
+    ("smc", Command::Smc),
+    ("break", Command::Break), ("b", Command::Break),
+    ("tbreak", Command::TBreak),
+    ("watch", Command::Watch),
+    ("rwatch", Command::RWatch),
+    ("awatch", Command::AWatch),
+    ("trap", Command::Trap),
+    ("loop", Command::Loop),
+    ("info", Command::Info),
+    ("delete", Command::Delete), ("d", Command::Delete),
+    ("enable", Command::Enable),
+    ("disable", Command::Disable),
+    ("ignore", Command::Ignore),
+    ("cont", Command::Continue), ("c", Command::Continue),
+    ("next", Command::Step), ("s", Command::Step), ("step", Command::Step),
+    ("over", Command::Over), ("o", Command::Over),
+    ("finish", Command::Finish), ("f", Command::Finish),
+    ("until", Command::Until), ("u", Command::Until),
+    ("back", Command::Back), ("rstep", Command::Back),
+    ("rcont", Command::ReverseContinue), ("rc", Command::ReverseContinue),
+    ("exit", Command::Exit), ("q", Command::Exit),
+    ("source", Command::Source),
+    ("define", Command::Define),
+    ("commands", Command::Commands),
+];
+
+// Where a continuous run started by a stepping command should stop
+pub enum StepTarget {
+    Steps(usize),                       // Amount of instructions left to execute
+    Over { return_pc: u16, sp: u8 },    // Return address of a JSR and the stack pointer before the call
+    Finish { sp: u8 },                  // Stack pointer of the frame we want to leave, right after its return address was pushed
+    Until(u16),
+    Line { from: Option<SourceLocation>, depth: Option<usize> },  // With a call depth, calls made from the line are stepped over
+}
+
+// What the prompt does after a command
+#[derive(PartialEq)]
+pub enum CommandResult {
+    Prompt,     // Read the next command
+    Resume,
+    Quit,
+}
 
 pub struct CpuRunner {
     pub cpu: Cpu,
     pub op_count: usize,
-    pub instruction_history: [Instruction; HISTORY_SIZE],
-    pub register_history: [CpuState; HISTORY_SIZE],
-    pub pc_traps: HashMap<u16,String>,
+    pub instruction_history: Vec<Instruction>,     // Ring buffers of history_depth slots indexed by op_count, empty at depth 0
+    pub register_history: Vec<CpuState>,
+    pub memory_history: Vec<Vec<(u16, u8)>>,   // Write journal of every instruction in the history, used to undo them
+    pub reversible_steps: usize,
+    pub call_stack: CallStack,
+    call_history: Vec<CallStackChange>,
+    pub breakpoints: BreakpointList,
+    pub watch_hit: Option<WatchHit>,    // Set by step when a watchpoint stops execution
+    pub symbols: SymbolTable,
+    pub debug_info: Option<DebugInfo>,
     pub continuous_run: bool,
+    pub step_target: Option<StepTarget>,
+    pub pending_commands: VecDeque<(String, usize)>,    // Script, macro and breakpoint commands with their macro depth, run before reading stdin
+    command_depth: usize,       // How deep in macros the command being run was queued
+    pub macros: HashMap<String, Vec<String>>,
+    pub batch_mode: bool,                       // Quit once the pending commands run out instead of reading stdin
+    pub cheat_finder: Option<CheatFinder>,
+    pub history_depth: usize,   // How many executed instructions can be undone or listed
+    last_instruction: Instruction,  // Executed by the last step, kept with or without a history
+    last_state: CpuState,           // Registers from before the last step
+    pub trace_file: Option<BufWriter<File>>,
+    pub trace_format: TraceFormat,
+    pub loop_detector: LoopDetector,
+    pub loop_hit: Option<LoopHit>,      // Set by step when the program is stuck in a loop
+    line_editor: Option<LineEditor>,    // Reads from stdin directly without one
+    last_command: Option<String>,       // Repeated by an empty line at the prompt
+    pub interrupt_requested: Arc<AtomicBool>,   // Set by the Ctrl+C handler to stop a continuous run
+    pub quit_requested: Arc<AtomicBool>,        // Set by a second Ctrl+C, quits before the next command
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub access_map: Option<AccessMap>,
+    pub smc_detector: SmcDetector,
+    pub smc_hit: Option<SmcHit>,        // Set by step when the program modifies code it executed
+    pub stack_checker: StackChecker,
+    pub stack_issue: Option<StackIssue>,    // Set by step when the program misuses the stack
 }
 
 // 
 impl CpuRunner {
     pub fn new() -> Self{
-        CpuRunner { cpu: Cpu::new(), op_count: 0, instruction_history: [Instruction::new(); HISTORY_SIZE], register_history: [CpuState::new(); HISTORY_SIZE], pc_traps: HashMap::new(), continuous_run: false }
-    }
-
-    pub fn add_trap(&mut self, loc: u16, message: String) {
-        self.pc_traps.insert(loc, message);
+        let mut runner = CpuRunner { cpu: Cpu::new(), op_count: 0, instruction_history: Vec::new(), register_history: Vec::new(), memory_history: Vec::new(), reversible_steps: 0, call_stack: CallStack::new(), call_history: Vec::new(), breakpoints: BreakpointList::new(), watch_hit: None, symbols: SymbolTable::new(), debug_info: None, continuous_run: false, step_target: None, pending_commands: VecDeque::new(), command_depth: 0, macros: HashMap::new(), batch_mode: false, cheat_finder: None, history_depth: 0, last_instruction: Instruction::new(), last_state: CpuState::new(), trace_file: None, trace_format: TraceFormat::Default, loop_detector: LoopDetector::new(), loop_hit: None, line_editor: None, last_command: None, interrupt_requested: Arc::new(AtomicBool::new(false)), quit_requested: Arc::new(AtomicBool::new(false)), profiler: None, coverage: None, access_map: None, smc_detector: SmcDetector::new(), smc_hit: None, stack_checker: StackChecker::new(), stack_issue: None };
+        runner.set_history_depth(DEFAULT_HISTORY_DEPTH);
+        runner
     }
 
     pub fn start_run(&mut self) {
         loop {
-            let ni = self.cpu.get_next_instruction();
-            self.instruction_history[self.op_count%HISTORY_SIZE] = ni;
-            let reg = self.cpu.get_cpu_state();
-            self.register_history[self.op_count%HISTORY_SIZE] = reg; 
-            
-            if self.cpu.pc == self.register_history[(self.op_count as i64 - 1).rem_euclid(HISTORY_SIZE as i64) as usize].pc {
-                println!("Found loop!");
+            if let Some(hit) = self.watch_hit.take() {
+                self.print_watch_hit(&hit);
+                let commands = self.breakpoints.iter().find(|bp| bp.id == hit.id).map(|bp| bp.commands.clone()).unwrap_or_default();
+                self.queue_commands(&commands, 0);
                 self.continuous_run = false;
             }
-            else {
-                if self.pc_traps.contains_key(&self.cpu.pc) {
-                    println!("Hit trap at pos {:04X}: {}", self.cpu.pc, self.pc_traps[&self.cpu.pc]);
+            
+            if let Some(hit) = self.smc_hit.take() {
+                println!("{}", self.describe_smc_hit(&hit));
+                if self.smc_detector.mode == SmcMode::Break {
+                    self.continuous_run = false;
+                }
+            }
+
+            if let Some(issue) = self.stack_issue.take() {
+                println!("{}", self.describe_stack_issue(&issue));
+                if self.stack_checker.mode == StackCheckMode::Break {
                     self.continuous_run = false;
                 }
             }
 
+            if let Some(hit) = self.loop_hit.take() {
+                self.print_loop_hit(&hit);
+                self.continuous_run = false;
+            }
+
+            // Also when something else stopped the run, so its commands run and its hit counts stay right
+            if let Some(bp) = self.breakpoints.hit(self.cpu.pc) {
+                match bp.message {
+                    Some(message) => println!("Hit breakpoint {} at pos {}: {}", bp.id, self.format_address(self.cpu.pc), message),
+                    None => println!("Hit breakpoint {} at pos {}", bp.id, self.format_address(self.cpu.pc)),
+                }
+                self.queue_commands(&bp.commands, 0);
+                self.continuous_run = false;
+            }
+
+            if self.continuous_run && self.reached_step_target() {
+                self.continuous_run = false;
+            }
+
+            if self.continuous_run && self.interrupt_requested.load(Ordering::Relaxed) {
+                println!("Interrupted at {}", self.format_address(self.cpu.pc));
+                self.continuous_run = false;
+            }
+
             if !self.continuous_run {
+                self.step_target = None;
                 self.print_cpu_state();
-                println!("Next instruction: {}", ni);
+                self.print_next_instruction(&self.cpu.get_next_instruction());
             }
 
             if !self.continuous_run {
                 let should_break = self.handle_input();
                 if should_break {
+                    // Closing the trace flushes it and reports a failed write, which dropping it would not
+                    self.set_trace_file(None);
                     break;
                 }
+                // Ctrl+C at the prompt only clears the line, it should not stop the command just entered
+                self.interrupt_requested.store(false, Ordering::Relaxed);
+            }
+
+            self.step();
+        }
+    }
+
+    /// Executes the next instruction, recording it in the history and checking watchpoints
+    pub fn step(&mut self) {
+        // Findings only describe the last instruction, a front end that stopped for another reason never takes them
+        self.loop_hit = None;
+        self.smc_hit = None;
+        self.stack_issue = None;
+        let instruction = self.cpu.get_next_instruction();
+        let before = self.cpu.get_cpu_state();
+        let watching = self.breakpoints.has_watchpoints();
+        let mapping = self.access_map.as_ref().is_some_and(|map| map.recording);
+        let mut reads = Vec::new();
+        let mut pointer_reads = Vec::new();
+        if watching || mapping {
+            self.cpu.get_next_data_reads(&mut reads);
+        }
+        if mapping {
+            self.cpu.get_next_pointer_reads(&mut pointer_reads);
+        }
+
+        if self.trace_file.is_some() {
+            self.write_trace_line(&instruction);
+        }
+
+        // Only journal writes when something looks at them
+        self.cpu.journal_writes = self.history_depth > 0 || watching || mapping || self.smc_detector.mode != SmcMode::Off;
+        let cycles_before = self.cpu.cycles;
+        let changes_before = self.cpu.memory_changes;
+        self.op_count += 1;
+        self.cpu.execute_next_instruction();
+        // Before the shadow stack is updated, so the instruction counts towards the frame it ran in
+        if let Some(profiler) = self.profiler.as_mut().filter(|profiler| profiler.recording) {
+            let called = matches!(instruction.operation, Operation::Jsr | Operation::Brk).then_some(self.cpu.pc);
+            profiler.record(before.pc, self.cpu.cycles - cycles_before, &self.call_stack.frames, called);
+        }
+        if let Some(coverage) = self.coverage.as_mut().filter(|coverage| coverage.recording) {
+            coverage.record(before.pc, &instruction, self.cpu.pc);
+        }
+        if let Some(issue) = self.stack_checker.check(&before, &instruction, &self.cpu, &self.call_stack.frames) {
+            self.stack_issue = Some(issue);
+        }
+        self.record_step_history(instruction, before);
+
+        let writes = if self.history_depth > 0 { &self.memory_history[(self.op_count - 1) % self.history_depth] } else { &self.cpu.write_journal };
+        if watching {
+            self.watch_hit = self.breakpoints.hit_watchpoint(&reads, writes);
+        }
+        let pc = before.pc;
+        let size = instruction.address_mode.address_size() as u16 + 1;
+        if let Some(map) = self.access_map.as_mut().filter(|map| map.recording) {
+            map.record(pc, size, &reads, &pointer_reads, writes);
+        }
+        // The cpu decodes from memory on every step, front ends holding decoded code refresh it from smc_detector.patched
+        if let Some(hit) = self.smc_detector.update(pc, size, writes, &self.cpu.memory) {
+            self.smc_hit = Some(hit);
+        }
+        self.cpu.write_journal.clear();
+        // Writing the value that is already there, like a JSR pushing the same return address again, changes nothing
+        let memory_changed = self.cpu.memory_changes != changes_before;
+        if let Some(hit) = self.loop_detector.update(&self.cpu.get_cpu_state(), memory_changed) {
+            self.loop_hit = Some(hit);
+        }
+    }
+
+    /// Writes memory on behalf of the debugger, through the same bus path the cpu writes, and checks write watchpoints.
+    /// Stepping back cannot undo the writes, so the recorded history is dropped.
+    pub fn poke(&mut self, address: u16, bytes: &[u8]) -> Option<WatchHit> {
+        let writes: Vec<(u16, u8)> = bytes.iter().enumerate().map(|(offset, value)| {
+            let address = address.wrapping_add(offset as u16);
+            let old_value = self.cpu.memory[address as usize];
+            self.cpu.write_memory_u8(address, *value);
+            (address, old_value)
+        }).collect();
+        let hit = if self.breakpoints.has_watchpoints() {
+            self.breakpoints.hit_watchpoint(&[], &writes)
+        }
+        else {
+            None
+        };
+        self.cpu.write_journal.clear();
+        self.reversible_steps = 0;
+        self.loop_detector.restart(&self.cpu.get_cpu_state());
+        hit
+    }
+
+    /// Sets how many executed instructions are kept for hist and stepping back, where 0 disables both.
+    /// The recorded history is dropped.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history_depth = depth;
+        self.instruction_history = vec![Instruction::new(); depth];
+        self.register_history = vec![CpuState::new(); depth];
+        self.memory_history = vec![Vec::new(); depth];
+        self.call_history = vec![CallStackChange::default(); depth];
+        self.reversible_steps = 0;
+    }
+
+    /// Streams every executed instruction to `filename`, or stops tracing with None
+    pub fn set_trace_file(&mut self, filename: Option<&str>) {
+        if let Some(mut file) = self.trace_file.take() {
+            if let Err(err) = file.flush() {
+                println!("Could not write the trace: {}", err);
+            }
+        }
+        if let Some(filename) = filename {
+            match File::create(filename) {
+                Ok(file) => self.trace_file = Some(BufWriter::new(file)),
+                Err(err) => println!("Could not create {}: {}", filename, err),
+            }
+        }
+    }
+
+    // One line per instruction, with the registers and cycle count from before it runs
+    fn write_trace_line(&mut self, instruction: &Instruction) {
+        let line = match self.trace_format {
+            TraceFormat::Default => format!("{:<40} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}", self.disassemble(instruction, self.cpu.pc), self.cpu.a, self.cpu.x, self.cpu.y, self.cpu.sr, self.cpu.sp, self.cpu.cycles),
+            TraceFormat::Nestest => nestest_line(&self.cpu),
+        };
+        if let Some(file) = &mut self.trace_file {
+            if let Err(err) = writeln!(file, "{}", line) {
+                println!("Tracing stopped, could not write the trace: {}", err);
+                self.trace_file = None;
             }
+        }
+    }
+
+    fn record_step_history(&mut self, instruction: Instruction, before: CpuState) {
+        let warning = if self.history_depth > 0 {
+            let index = (self.op_count - 1) % self.history_depth;
+            self.instruction_history[index] = instruction;
+            self.register_history[index] = before;
+            std::mem::swap(&mut self.memory_history[index], &mut self.cpu.write_journal);
+            self.call_stack.update(&before, &instruction, &self.cpu, &mut self.call_history[index])
+        }
+        else {
+            // Nothing can undo the step, so the change to the call stack is not kept either
+            self.call_stack.update(&before, &instruction, &self.cpu, &mut CallStackChange::default())
+        };
+        self.last_instruction = instruction;
+        self.last_state = before;
+        if let Some(warning) = warning {
+            // Only report right away when stepping, `bt` shows the last one otherwise
+            if !self.continuous_run {
+                println!("Call stack: {}", warning);
+            }
+        }
+
+        self.reversible_steps = (self.reversible_steps + 1).min(self.history_depth);
+    }
+
+    /// Undoes the last executed instruction, restoring registers and memory.
+    /// Returns false if the history does not reach further back.
+    pub fn step_back(&mut self) -> bool {
+        if self.reversible_steps == 0 {
+            return false;
+        }
+        self.op_count -= 1;
+        let index = self.op_count % self.history_depth;
+        // Restore directly instead of through write_memory_u8, undoing is not a new write
+        for (address, old_value) in self.memory_history[index].iter().rev() {
+            self.cpu.memory[*address as usize] = *old_value;
+        }
+        self.memory_history[index].clear();
+        self.call_stack.undo(&self.call_history[index]);
+        self.cpu.set_cpu_state(&self.register_history[index]);
+        self.reversible_steps -= 1;
+        self.loop_detector.restart(&self.cpu.get_cpu_state());
+        true
+    }
+
+    /// Runs until the innermost frame on the shadow call stack returns, or without one until a return raises SP above its current value
+    pub fn finish_target(&self) -> StepTarget {
+        let sp = self.call_stack.frames.last().map(|frame| frame.sp_at_entry).unwrap_or(self.cpu.sp);
+        StepTarget::Finish { sp }
+    }
 
-            self.op_count += 1;
-            self.cpu.execute_next_instruction();
+    pub fn reached_step_target(&mut self) -> bool {
+        let last_instruction = self.last_instruction;
+        match &mut self.step_target {
+            None => false,
+            Some(StepTarget::Steps(remaining)) => {
+                if *remaining == 0 {
+                    return true;
+                }
+                *remaining -= 1;
+                false
+            },
+            // A recursive call returns to the same address, but with a lower stack pointer
+            Some(StepTarget::Over { return_pc, sp }) => self.cpu.pc == *return_pc && self.cpu.sp >= *sp,
+            // Returns from deeper frames never raise the stack pointer above the entry of the frame we are finishing
+            Some(StepTarget::Finish { sp }) => matches!(last_instruction.operation, Operation::Rts | Operation::Rti) && self.cpu.sp > *sp,
+            Some(StepTarget::Until(address)) => self.cpu.pc == *address,
+            Some(StepTarget::Line { from, depth }) => {
+                let location = self.debug_info.as_ref().and_then(|info| info.location_at(self.cpu.pc));
+                let in_call = depth.is_some_and(|depth| self.call_stack.frames.len() > depth);
+                location.is_some() && location != *from && !in_call
+            },
         }
     }
 
@@ -62,68 +412,271 @@ impl CpuRunner {
         println!("State: {}", self.cpu.get_cpu_state());
     }
     
+    pub fn print_next_instruction(&self, ni: &Instruction) {
+        println!("Next instruction: {}", self.disassemble(ni, self.cpu.pc));
+        if let Some(location) = self.source_location(self.cpu.pc) {
+            println!("    {}:{}: {}", self.debug_info.as_ref().unwrap().file_name(location.file), location.line, self.debug_info.as_ref().unwrap().source_line(&location).unwrap_or("").trim());
+        }
+    }
+
+    pub fn source_location(&self, address: u16) -> Option<SourceLocation> {
+        self.debug_info.as_ref().and_then(|info| info.location_at(address))
+    }
+
     pub fn print_instruction(&self, pos: u16){
-        println!("{}", self.cpu.get_instruction_at(pos));
+        println!("{}", self.disassemble(&self.cpu.get_instruction_at(pos), pos));
+    }
+
+    /// The address in hex, followed by its label if it has one
+    pub fn format_address(&self, address: u16) -> String {
+        match self.symbols.name_at(address) {
+            Some(name) => format!("{:04X} <{}>", address, name),
+            None => format!("{:04X}", address),
+        }
+    }
+
+    pub fn disassemble(&self, instruction: &Instruction, pc: u16) -> String {
+        format!("{} {}", self.format_address(pc), format_instruction(instruction, pc, &self.symbols))
+    }
+
+    /// Address of the instruction `offset` instructions away from `base`.
+    /// Instructions differ in length, so walking backwards starts from the furthest point that decodes into `base`.
+    pub fn instruction_start(&self, base: u16, offset: i64) -> u16 {
+        if offset >= 0 {
+            return (0..offset).fold(base, |address, _| address.wrapping_add(self.instruction_length(address)));
+        }
+        let count = offset.unsigned_abs().min(0x4000) as u16;
+        for distance in (count..=count * 3).rev() {
+            let start = base.wrapping_sub(distance);
+            let mut lengths = Vec::new();
+            let mut walked = 0;
+            while walked < distance {
+                let length = self.instruction_length(start.wrapping_add(walked));
+                lengths.push(length);
+                walked += length;
+            }
+            if walked == distance && lengths.len() >= count as usize {
+                let skipped: u16 = lengths[..lengths.len() - count as usize].iter().sum();
+                return start.wrapping_add(skipped);
+            }
+        }
+        base.wrapping_sub(count)
+    }
+
+    pub fn instruction_length(&self, address: u16) -> u16 {
+        1 + self.cpu.get_instruction_at(address).address_mode.address_size() as u16
+    }
+
+    pub fn load_symbols(&mut self, filename: &str) {
+        match self.symbols.load_file(filename) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, filename),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    /// Loads source line information from an ld65 debug file, along with its labels
+    pub fn load_debug_info(&mut self, filename: &str) {
+        match DebugInfo::load_file(filename) {
+            Ok(info) => {
+                let missing: Vec<&str> = info.files.values().filter(|file| file.lines.is_none()).map(|file| file.name.as_str()).collect();
+                println!("Loaded line information for {} source files from {}", info.files.len(), filename);
+                if !missing.is_empty() {
+                    println!("Could not find the source of {}", missing.join(", "));
+                }
+                self.debug_info = Some(info);
+                self.load_symbols(filename);
+            },
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    /// Prints the source around a location, marking the location itself
+    pub fn print_source(&self, location: &SourceLocation, context: usize) {
+        let info = match &self.debug_info {
+            Some(info) => info,
+            None => return,
+        };
+        println!("{}", info.file_name(location.file));
+        let first = location.line.saturating_sub(context).max(1);
+        for line in first..=location.line + context {
+            match info.source_line(&SourceLocation { file: location.file, line }) {
+                Some(text) => println!("{} {:<5} {}", if line == location.line {">"} else {" "}, line, text),
+                None => {
+                    if line == location.line {
+                        println!("Source line {} is not available", line);
+                    }
+                    break;
+                },
+            }
+        }
+    }
+
+    /// Evaluates an argument expression, reading numbers without a prefix in `radix`.
+    /// Commands split their arguments on spaces, so an expression argument is written without any, e.g. break PC+3.
+    pub fn evaluate(&self, arg: &str, radix: u32) -> Result<i64, ExpressionError> {
+        evaluate(arg, &self.cpu, &self.symbols, radix)
+    }
+
+    pub fn parse_address(&self, arg: &str) -> Option<u16> {
+        self.evaluate(arg, 16).ok().and_then(|value| u16::try_from(value).ok())
     }
     
     pub fn print_history(&self, instruction_amount: u16) {
-        let mut counter = if self.op_count > instruction_amount as usize {self.op_count - instruction_amount as usize} else {0};
-        while counter <= self.op_count{
-            let wrapped_index = counter % HISTORY_SIZE;
-            print!("Step {}: {} || ", counter as i64 - self.op_count as i64, self.instruction_history[wrapped_index]);
-            println!("Regs: {}", self.register_history[wrapped_index]);
-            counter += 1;
+        // Older entries have been overwritten in the ring buffer
+        let instruction_amount = (instruction_amount as usize).min(self.history_depth);
+        for age in (1..=instruction_amount.min(self.op_count)).rev() {
+            let (instruction, registers) = self.history_entry(age);
+            println!("Step -{}: {} || Regs: {}", age, self.disassemble(instruction, registers.pc), registers);
+        }
+        let registers = self.cpu.get_cpu_state();
+        println!("Step 0: {} || Regs: {}", self.disassemble(&self.cpu.get_next_instruction(), registers.pc), registers);
+    }
+
+    /// The instruction executed `age` steps ago, 1 being the last one, with the registers before it
+    pub fn history_entry(&self, age: usize) -> (&Instruction, &CpuState) {
+        let index = (self.op_count - age) % self.history_depth;
+        (&self.instruction_history[index], &self.register_history[index])
+    }
+
+    pub fn print_watch_hit(&self, hit: &WatchHit) {
+        let accessed_by = self.disassemble(&self.last_instruction, self.last_state.pc);
+        match hit.old_value {
+            Some(old_value) => println!("Hit watchpoint {}: write to {} {:02X} -> {:02X} by {}", hit.id, self.format_address(hit.address), old_value, self.cpu.memory[hit.address as usize], accessed_by),
+            None => println!("Hit watchpoint {}: read of {} by {}", hit.id, self.format_address(hit.address), accessed_by),
+        }
+    }
+
+    pub fn print_loop_hit(&self, hit: &LoopHit) {
+        println!("{}", self.describe_loop_hit(hit));
+    }
+
+    pub fn describe_loop_hit(&self, hit: &LoopHit) -> String {
+        match hit.trap {
+            Some((address, kind)) => format!("Reached {} trap at {}", kind.name(), self.format_address(address)),
+            None => format!("Found loop at {}, {} instructions long", self.format_address(hit.pc), hit.length),
+        }
+    }
+
+    pub fn describe_smc_hit(&self, hit: &SmcHit) -> String {
+        format!("Self-modifying code at {}: wrote {} over executed {} {:02X} -> {:02X}", self.format_address(hit.pc), self.format_address(hit.address), if hit.operand {"operand"} else {"opcode"}, hit.old_value, hit.new_value)
+    }
+
+    pub fn describe_stack_issue(&self, issue: &StackIssue) -> String {
+        let instruction = format!("{} at {}", issue.operation.as_ref().to_uppercase(), self.format_address(issue.pc));
+        match issue.kind {
+            StackIssueKind::Overflow => format!("Stack overflow: {} wrapped SP from {:02X} to {:02X}", instruction, issue.sp_before, issue.sp_after),
+            StackIssueKind::Underflow => format!("Stack underflow: {} wrapped SP from {:02X} to {:02X}", instruction, issue.sp_before, issue.sp_after),
+            StackIssueKind::Unbalanced { routine, entry_sp } => {
+                format!("Unbalanced stack: {} returned from {} with SP {:02X}, it was entered with SP {:02X}, and went to {}", instruction, self.format_address(routine), issue.sp_before, entry_sp, self.format_address(issue.pc_after))
+            },
+            StackIssueKind::UnpushedReturn { pushed: Some(pushed) } => {
+                format!("Return to an address no call pushed: {} went to {} instead of {}", instruction, self.format_address(issue.pc_after), self.format_address(pushed))
+            },
+            StackIssueKind::UnpushedReturn { pushed: None } => format!("Return to an address no call pushed: {} went to {} outside of any call", instruction, self.format_address(issue.pc_after)),
+        }
+    }
+
+    /// Lists the stack from SP+1 to $01FF with what each entry most likely is
+    pub fn print_stack(&self) {
+        let entries = inspect_stack(&self.cpu, &self.call_stack.frames);
+        if entries.is_empty() {
+            println!("Stack is empty, SP: {:02X}", self.cpu.sp);
+            return;
+        }
+        println!("SP: {:02X}, {} bytes in use", self.cpu.sp, 0xFF - self.cpu.sp as usize);
+        for entry in entries {
+            let bytes: Vec<String> = entry.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let meaning = match entry.kind {
+                StackEntryKind::ReturnAddress { return_address, call_site, target, guessed } => format!("{}return address {}, JSR {} at {}",
+                    if guessed { "possible " } else { "" }, self.format_address(return_address), self.format_address(target), self.format_address(call_site)),
+                StackEntryKind::Interrupt { status, return_address } => format!("interrupt frame, {}, return to {}", format_status(status), self.format_address(return_address)),
+                StackEntryKind::Data => "data".to_string(),
+            };
+            println!("{:04X}: {:<9} {}", entry.address, bytes.join(" "), meaning);
         }
     }
 
-    pub fn dump_memory(&self, filename: &str) {
-        let mut file = File::create(filename).expect("Could not create file");
-        file.write(&self.cpu.memory).expect("Could not write memory to file!");
+    pub fn print_backtrace(&self) {
+        println!("PC: {}", self.format_address(self.cpu.pc));
+        if self.call_stack.frames.is_empty() {
+            println!("No frames on the call stack");
+        }
+        else {
+            println!("#    Type  Routine  Caller  SP   Return  Symbol");
+            for (depth, frame) in self.call_stack.frames.iter().rev().enumerate() {
+                println!("{:<4} {}    {}", depth, frame, self.symbols.name_at(frame.target).unwrap_or(""));
+            }
+        }
+        if let Some(desync) = &self.call_stack.last_desync {
+            println!("Warning: stack manipulation detected {} time(s), last: {}", self.call_stack.desync_count, desync);
+        }
+    }
+
+    /// Writes `length` bytes from `start` to a file, stopping at the end of memory
+    pub fn dump_memory(&self, filename: &str, start: u16, length: usize) -> std::io::Result<usize> {
+        let end = (start as usize + length).min(self.cpu.memory.len());
+        File::create(filename)?.write_all(&self.cpu.memory[start as usize..end])?;
+        Ok(end - start as usize)
     }
     
     pub fn print_hex_table(&self, size: usize, start_index: usize) {
-        let mut index = start_index;
-        // calculate how many spaces to pad before start index
-        let padding = start_index % 16;
-        // We want to count how many bytes we've printed as opposed to indices
-        let mut count = 0;
-        // print header
-        println!("      00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F");
-        println!("      -----------------------------------------------");
-        
-        // To add padding at the beginning we copy the amount of padding and count down at the first iteration
-        // This also serves to align index to 16 bytes
-        let mut padding_align = padding;
-        if padding_align != 0 {
-            print!("{:04x}: ", index - padding);
-            while padding_align > 0 {
-                print!("   ");
-                padding_align -= 1;
-            }
-            for _i in padding..16 {
-                if count < size {
-                    print!("{:02x} ", self.cpu.memory[index]);
-                } else {
-                    print!("   ");
-                }
-                count += 1;
-                index += 1;
-            }
-            println!("");
+        self.print_memory(start_index, size, MemoryFormat::Hex);
+    }
+
+    /// Prints `size` bytes from `start` in rows that begin at a multiple of the row length, stopping at the end of memory
+    pub fn print_memory(&self, start: usize, size: usize, format: MemoryFormat) {
+        let end = (start + size).min(self.cpu.memory.len());
+        let unit = format.unit_size();
+        if format == MemoryFormat::Pointers {
+            for address in (start..end).step_by(unit) {
+                println!("{:04X}: {}", address, self.format_address(self.cpu.read_memory_u16(address as u16)));
+            }
+            return;
         }
-    
-        while count < size {
-            print!("{:04x}: ", index);
-            for _i in 0..16 {    
-                if count < size {
-                    print!("{:02X} ", self.cpu.memory[index]);
-                } else {
-                    print!("   ");
+
+        let row_length = format.row_length();
+        let width = format.cell_width();
+        // Words from an odd address are lined up with it
+        let phase = start % unit;
+        let columns: Vec<String> = (phase..row_length).step_by(unit).map(|offset| format!("{:>width$}", format!("{:02X}", offset), width = width)).collect();
+        println!("      {}", columns.join(" "));
+        println!("      {}", "-".repeat(columns.len() * (width + 1) - 1));
+
+        // The first row is padded up to the start address
+        let mut row = start - start % row_length;
+        while row < end {
+            print!("{:04X}: ", row);
+            for address in (row + phase..row + row_length).step_by(unit) {
+                if address >= start && address < end {
+                    let high = self.cpu.memory[(address + 1) & 0xFFFF];
+                    print!("{} ", format.format_cell(self.cpu.memory[address], high));
+                }
+                else {
+                    print!("{:width$} ", "", width = width);
                 }
-                count += 1;
-                index += 1;
             }
-            println!("");
+            if format.text_char(0).is_some() {
+                let text: String = (row..row + row_length)
+                    .map(|address| if address >= start && address < end { format.text_char(self.cpu.memory[address]).unwrap_or('.') } else { ' ' })
+                    .collect();
+                print!("|{}| ", text);
+            }
+            self.print_row_labels(row, row_length);
+            row += row_length;
+        }
+    }
+
+    // Ends a memory table row, listing the labels in it by their column
+    fn print_row_labels(&self, row_start: usize, row_length: usize) {
+        let row_start = row_start as u16;
+        let labels: Vec<String> = self.symbols.names_in_range(row_start, row_start.saturating_add(row_length as u16 - 1))
+            .map(|(address, name)| format!("{:02X}:{}", address - row_start, name))
+            .collect();
+        if labels.is_empty() {
+            println!();
+        }
+        else {
+            println!("; {}", labels.join(" "));
         }
     }
 }
@@ -131,126 +684,1821 @@ impl CpuRunner {
 impl CpuRunner {
     fn handle_input(&mut self) -> bool{
         loop {
-            let mut cmd = String::new();
-            stdin().read_line(&mut cmd).expect("Did not enter a correct string");
-            let split_cmd: Vec<&str> = cmd.split(" ").map(|val| val.trim()).collect();
-            if split_cmd[0].eq("mem_dec") {
-                self.print_mem_dec(split_cmd);
-            }
-            else if split_cmd[0].eq("mem") {
-                self.print_mem_hex(split_cmd);
-            }
-            else if split_cmd[0].eq("reg") {
-                self.print_cpu_state();
-            }
-            else if split_cmd[0].eq("op") {
-                self.print_instruction_cmd(split_cmd);
-            }
-            else if split_cmd[0].eq("hist") {
-                self.print_history_cmd(split_cmd);
-            }
-            else if split_cmd[0].eq("dump") {
-                self.dump_memory(split_cmd[1]);
-            }
-            else if split_cmd[0].eq("cont") || split_cmd[0].eq("c") {
-                self.continuous_run = true;
-                return false;
-            }
-            else if split_cmd[0].eq("next") || split_cmd[0].eq("s") {
-                return false;
-            }
-            else if split_cmd[0].eq("exit") || split_cmd[0].eq("q") {
+            if self.quit_requested.load(Ordering::Relaxed) {
+                println!("Quitting");
                 return true;
             }
-            else{
-                println!("No valid command was entered!");
+            let typed = self.pending_commands.is_empty();
+            let mut cmd = match self.next_command() {
+                Some(cmd) => cmd,
+                None => return true,
+            };
+            // Enter on an empty line at the prompt repeats the last command, e.g. to keep stepping.
+            // Only here, so blank lines in a define or commands block and queued commands are left alone.
+            if typed {
+                if cmd.trim().is_empty() {
+                    cmd = self.last_command.clone().unwrap_or(cmd);
+                }
+                else {
+                    self.last_command = Some(cmd.clone());
+                }
+            }
+            match self.execute_command(&cmd) {
+                CommandResult::Prompt => {},
+                CommandResult::Resume if !self.quit_requested.load(Ordering::Relaxed) => return false,
+                CommandResult::Resume => {},
+                CommandResult::Quit => return true,
             }
         }
     }
 
-    fn print_instruction_cmd(&self, cmds: Vec<&str>) {
-        let pos: u16;
-        if cmds.len() < 2 || cmds[1].eq("*") {
-            pos = self.cpu.pc;
-        }
-        else{
-            pos = match u16::from_str_radix(cmds[1], 16) {
-                Ok(num) => num,
-                Err(_) => { 
-                    println!("Invalid start value {}", cmds[1]);
-                    return;
-                },
-            };
+    /// Takes the next queued command, or reads one from stdin. Returns None once all input is used up.
+    fn next_command(&mut self) -> Option<String> {
+        if let Some((cmd, depth)) = self.pending_commands.pop_front() {
+            // Echo queued commands so the output of a script shows what produced it
+            println!("> {}", cmd);
+            self.command_depth = depth;
+            return Some(cmd);
         }
-        self.print_instruction(pos);
-    }
-    fn print_mem_dec(&self, cmds: Vec<&str>){
-        if cmds.len() < 2 {
-            println!("Invalid arguments {}", cmds[1]);
-            return;
+        self.command_depth = 0;
+        if self.batch_mode {
+            return None;
         }
-        let start: u16 = match cmds[1].trim().parse() {
-            Ok(num) => num,
-            Err(_) => { 
-                println!("Invalid start value {}", cmds[1]);
-                return;
+        let line = match &mut self.line_editor {
+            Some(editor) => {
+                let helper = editor.helper_mut();
+                helper.commands = COMMANDS.iter().map(|(name, _)| name.to_string()).chain(self.macros.keys().cloned()).collect();
+                helper.symbols = self.symbols.names().cloned().collect();
+                editor.read_line()?
             },
-        };
-    
-        let size: u16 =  match cmds[2].trim().parse() {
-            Ok(num) => num,
-            Err(_) => { 
-                println!("Invalid range value {}", cmds[2]);
-                return;
+            None => {
+                let mut line = String::new();
+                match stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => return None,
+                    Ok(_) => line,
+                }
             },
         };
-        
-        self.print_hex_table(size.into(), start.into());
+        Some(line)
     }
-    
-    fn print_mem_hex(&self, cmds: Vec<&str>){
-        let start: u16;
-        
-        if cmds.len() < 3 || cmds[1].eq("*") {
-            start = self.cpu.pc;
-        }
-        else{
-            start = match u16::from_str_radix(cmds[1], 16) {
-                Ok(num) => num,
-                Err(_) => { 
-                    println!("Invalid start value {}", cmds[1]);
-                    return;
-                },
-            };
-        } 
 
-        let size_str = if cmds.len() < 3 {cmds[1]} else {cmds[2]};
-
-        let size: u16 =  match u16::from_str_radix(size_str, 16) {
-            Ok(num) => num,
-            Err(_) => { 
-                println!("Invalid range value {}", size_str);
-                return;
-            },
-        };
-        self.print_hex_table(size.into(), start.into());
+    /// Reads commands with line editing, history and tab completion from now on
+    pub fn enable_line_editing(&mut self) {
+        match LineEditor::new() {
+            Ok(editor) => self.line_editor = Some(editor),
+            Err(err) => println!("Line editing is not available: {}", err),
+        }
     }
 
-    fn print_history_cmd(&self, cmds: Vec<&str>) {
-        let size: u16;
-        if cmds.len() < 2 || cmds[1].eq("*") {
-            size = 10;
-        }
-        else {
-            size = match cmds[1].parse() {
-                Ok(num) => num,
-                Err(_) => { 
-                    println!("Invalid size value {}", cmds[1]);
-                    return;
-                },
-            }
+    /// Runs a single debugger command, as typed at the prompt or read from a script
+    pub fn execute_command(&mut self, cmd: &str) -> CommandResult {
+        let cmd = cmd.trim();
+        if cmd.is_empty() || cmd.starts_with('#') {
+            return CommandResult::Prompt;
         }
-
-        self.print_history(size);
-    }
-}
\ No newline at end of file
+        let split_cmd: Vec<&str> = cmd.split(" ").map(|val| val.trim()).collect();
+        let command = match COMMANDS.iter().find(|(name, _)| *name == split_cmd[0]) {
+            Some((_, command)) => *command,
+            None => {
+                match self.macros.get(split_cmd[0]).cloned() {
+                    Some(body) => self.expand_macro(&body, &split_cmd[1..]),
+                    None => println!("No valid command was entered!"),
+                }
+                return CommandResult::Prompt;
+            },
+        };
+        match command {
+            Command::MemDec => {
+                self.print_mem_dec(split_cmd);
+            },
+            Command::Mem => {
+                self.print_mem_hex(split_cmd);
+            },
+            Command::Reg => {
+                self.print_cpu_state();
+            },
+            Command::Op => {
+                self.print_instruction_cmd(split_cmd);
+            },
+            Command::Hist => {
+                self.print_history_cmd(split_cmd);
+            },
+            Command::History => {
+                self.history_cmd(split_cmd);
+            },
+            Command::Trace => {
+                self.trace_cmd(split_cmd);
+            },
+            Command::TraceDiff => {
+                if split_cmd.len() < 3 || split_cmd[2].is_empty() {
+                    println!("Usage: tracediff <file> <file> [context lines]");
+                }
+                else {
+                    print_trace_diff(split_cmd[1], split_cmd[2], split_cmd.get(3).and_then(|lines| lines.parse().ok()));
+                }
+            },
+            Command::Backtrace => {
+                self.print_backtrace();
+            },
+            Command::Stack => {
+                if split_cmd.len() > 1 && split_cmd[1].eq("check") {
+                    self.stack_check_cmd(split_cmd);
+                }
+                else {
+                    self.print_stack();
+                }
+            },
+            Command::Symbols => {
+                if split_cmd.len() < 2 || split_cmd[1].is_empty() {
+                    println!("{} symbols loaded", self.symbols.len());
+                }
+                else {
+                    self.load_symbols(split_cmd[1]);
+                }
+            },
+            Command::DebugInfo => {
+                if split_cmd.len() < 2 || split_cmd[1].is_empty() {
+                    println!("Usage: dbginfo <file.dbg>");
+                }
+                else {
+                    self.load_debug_info(split_cmd[1]);
+                }
+            },
+            Command::List => {
+                self.list_cmd(split_cmd);
+            },
+            Command::StepLine => {
+                if self.line_step_cmd(false) {
+                    return CommandResult::Resume;
+                }
+            },
+            Command::NextLine => {
+                if self.line_step_cmd(true) {
+                    return CommandResult::Resume;
+                }
+            },
+            Command::Dump => {
+                self.dump_cmd(split_cmd);
+            },
+            Command::Load => {
+                self.load_cmd(split_cmd);
+            },
+            Command::Set => {
+                self.set_cmd(split_cmd);
+            },
+            Command::Poke => {
+                self.poke_cmd(split_cmd);
+            },
+            Command::Fill => {
+                self.fill_cmd(split_cmd);
+            },
+            Command::Copy => {
+                self.copy_cmd(split_cmd);
+            },
+            Command::Find => {
+                self.find_cmd(split_cmd);
+            },
+            Command::Cheat => {
+                self.cheat_cmd(split_cmd);
+            },
+            Command::Profile => {
+                self.profile_cmd(split_cmd);
+            },
+            Command::Coverage => {
+                self.coverage_cmd(split_cmd);
+            },
+            Command::Access => {
+                self.access_cmd(split_cmd);
+            },
+            Command::Disasm => {
+                self.disasm_cmd(split_cmd);
+            },
+            Command::Smc => {
+                self.smc_cmd(split_cmd);
+            },
+            Command::Break => {
+                self.break_cmd(split_cmd, false);
+            },
+            Command::TBreak => {
+                self.break_cmd(split_cmd, true);
+            },
+            Command::Watch => {
+                self.watch_cmd(split_cmd, BreakpointKind::Write);
+            },
+            Command::RWatch => {
+                self.watch_cmd(split_cmd, BreakpointKind::Read);
+            },
+            Command::AWatch => {
+                self.watch_cmd(split_cmd, BreakpointKind::Access);
+            },
+            Command::Trap => {
+                self.trap_cmd(split_cmd);
+            },
+            Command::Loop => {
+                self.loop_cmd(split_cmd);
+            },
+            Command::Info => {
+                self.info_cmd(split_cmd);
+            },
+            Command::Delete => {
+                self.delete_cmd(split_cmd);
+            },
+            Command::Enable => {
+                self.enable_cmd(split_cmd, true);
+            },
+            Command::Disable => {
+                self.enable_cmd(split_cmd, false);
+            },
+            Command::Ignore => {
+                self.ignore_cmd(split_cmd);
+            },
+            Command::Continue => {
+                self.continuous_run = true;
+                return CommandResult::Resume;
+            },
+            Command::Step => {
+                if self.step_cmd(split_cmd) {
+                    return CommandResult::Resume;
+                }
+            },
+            Command::Over => {
+                self.over_cmd();
+                return CommandResult::Resume;
+            },
+            Command::Finish => {
+                self.step_target = Some(self.finish_target());
+                self.continuous_run = true;
+                return CommandResult::Resume;
+            },
+            Command::Until => {
+                if self.until_cmd(split_cmd) {
+                    return CommandResult::Resume;
+                }
+            },
+            Command::Back => {
+                self.back_cmd(split_cmd);
+            },
+            Command::ReverseContinue => {
+                self.reverse_continue();
+            },
+            Command::Exit => {
+                return CommandResult::Quit;
+            },
+            Command::Source => {
+                if split_cmd.len() < 2 || split_cmd[1].is_empty() {
+                    println!("Usage: source <file>");
+                }
+                else {
+                    self.source_file(split_cmd[1]);
+                }
+            },
+            Command::Define => {
+                self.define_cmd(split_cmd);
+            },
+            Command::Commands => {
+                self.commands_cmd(split_cmd);
+            },
+        }
+        CommandResult::Prompt
+    }
+
+    fn print_instruction_cmd(&self, cmds: Vec<&str>) {
+        if let Some(pos) = self.parse_address_arg(&cmds, 1) {
+            self.print_instruction(pos);
+        }
+    }
+    // Like mem, but numbers without a prefix are decimal
+    fn print_mem_dec(&self, cmds: Vec<&str>){
+        let (cmds, format) = split_memory_format(&cmds);
+        if cmds.len() < 3 || cmds[2].is_empty() {
+            println!("Usage: mem_dec <start> <size> [format]");
+            return;
+        }
+        let start = match self.parse_value(cmds[1], 10, 0xFFFF, "start value") {
+            Some(num) => num,
+            None => return,
+        };
+        let size = match self.parse_value(cmds[2], 10, 0xFFFF, "range value") {
+            Some(num) => num,
+            None => return,
+        };
+        
+        self.print_memory(start as usize, size as usize, format);
+    }
+    
+    /// mem [start] <size> [format], where a single argument is the size shown from the PC.
+    /// A single pointer to follow is the start instead, e.g. mem *[$20] shows where the word at $20 points.
+    fn print_mem_hex(&self, cmds: Vec<&str>){
+        let (cmds, format) = split_memory_format(&cmds);
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Usage: mem [start] <size> [hex|ascii|petscii|atascii|words|signed|binary|pointers]");
+            return;
+        }
+        let start = if cmds.len() < 3 && !cmds[1].starts_with("*[") {
+            self.cpu.pc
+        }
+        else {
+            match self.parse_address_arg(cmds, 1) {
+                Some(num) => num,
+                None => return,
+            }
+        };
+
+        let size = if cmds.len() < 3 && cmds[1].starts_with("*[") {
+            FOLLOWED_POINTER_SIZE
+        }
+        else {
+            let size_str = if cmds.len() < 3 {cmds[1]} else {cmds[2]};
+            match self.parse_value(size_str, 16, 0xFFFF, "range value") {
+                Some(num) => num as usize,
+                None => return,
+            }
+        };
+        self.print_memory(start.into(), size, format);
+    }
+
+    fn print_history_cmd(&self, cmds: Vec<&str>) {
+        let size = if cmds.len() < 2 || cmds[1].eq("*") {
+            10
+        }
+        else {
+            match self.parse_value(cmds[1], 10, u16::MAX as i64, "size value") {
+                Some(num) => num as u16,
+                None => return,
+            }
+        };
+        self.print_history(size);
+    }
+
+    /// trap success|failure <address> marks where a test program ends up, trap delete <address> removes one, trap lists them
+    fn trap_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            if self.loop_detector.traps.is_empty() {
+                println!("No traps.");
+            }
+            for (address, kind) in self.loop_detector.traps.iter() {
+                println!("{} trap at {}", kind.name(), self.format_address(*address));
+            }
+            return;
+        }
+        if cmds.len() < 3 || cmds[2].is_empty() {
+            println!("Usage: trap success|failure|delete <address>");
+            return;
+        }
+        let address = match self.parse_address_arg(&cmds, 2) {
+            Some(address) => address,
+            None => return,
+        };
+        if cmds[1].eq("success") || cmds[1].eq("failure") {
+            let kind = if cmds[1].eq("success") { TrapKind::Success } else { TrapKind::Failure };
+            self.loop_detector.traps.insert(address, kind);
+            println!("{} trap at {}", kind.name(), self.format_address(address));
+        }
+        else if cmds[1].eq("delete") {
+            if self.loop_detector.traps.remove(&address).is_none() {
+                println!("No trap at {}", self.format_address(address));
+            }
+        }
+        else {
+            println!("Usage: trap success|failure|delete <address>");
+        }
+    }
+
+    /// loop <iterations> [max length] sets how often a state has to repeat before stopping, loop off disables the check
+    fn loop_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            match self.loop_detector.iterations {
+                0 => println!("Loop detection is off"),
+                iterations => println!("Stopping after {} iterations of loops up to {} instructions long", iterations, self.loop_detector.max_length),
+            }
+            return;
+        }
+        let iterations = if cmds[1].eq("off") { Some(0) } else { self.parse_count(cmds[1], "iteration count") };
+        let max_length = match cmds.get(2) {
+            None | Some(&"") => Some(self.loop_detector.max_length),
+            Some(length) => self.parse_count(length, "loop length"),
+        };
+        if let (Some(iterations), Some(max_length)) = (iterations, max_length) {
+            self.loop_detector.iterations = iterations;
+            self.loop_detector.max_length = max_length;
+            self.loop_detector.reset();
+        }
+    }
+
+    /// history [depth] shows or sets how many executed instructions are recorded
+    fn history_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Recording the last {} instructions, {} can be undone", self.history_depth, self.reversible_steps);
+            return;
+        }
+        if let Some(depth) = self.parse_count(cmds[1], "history depth") {
+            self.set_history_depth(depth);
+            println!("Recording the last {} instructions", depth);
+        }
+    }
+
+    /// trace on <file> [nestest] or trace off, where nestest writes lines in the format of nestest.log
+    fn trace_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() > 2 && cmds[1].eq("on") && !cmds[2].is_empty() {
+            self.trace_format = match cmds.get(3) {
+                None | Some(&"") => TraceFormat::Default,
+                Some(&"nestest") => TraceFormat::Nestest,
+                Some(format) => {
+                    println!("Unknown trace format {}, expected nestest", format);
+                    return;
+                },
+            };
+            self.set_trace_file(Some(cmds[2]));
+            if self.trace_file.is_some() {
+                println!("Tracing to {}", cmds[2]);
+            }
+        }
+        else if cmds.len() > 1 && cmds[1].eq("off") {
+            self.set_trace_file(None);
+            println!("Tracing stopped");
+        }
+        else {
+            println!("Usage: trace on <file> [nestest] or trace off");
+        }
+    }
+
+    /// Evaluates an address argument, where numbers are hex and a missing argument means the current PC
+    fn parse_address_arg(&self, cmds: &[&str], index: usize) -> Option<u16> {
+        if cmds.len() <= index || cmds[index].is_empty() {
+            return Some(self.cpu.pc);
+        }
+        self.parse_value(cmds[index], 16, 0xFFFF, "address").map(|address| address as u16)
+    }
+
+    /// Evaluates an argument that has to be in the range 0..=max, printing why if it is not
+    fn parse_value(&self, arg: &str, radix: u32, max: i64, what: &str) -> Option<i64> {
+        match self.evaluate(arg, radix) {
+            Ok(value) if (0..=max).contains(&value) => Some(value),
+            Ok(value) => {
+                println!("Invalid {} {}: {} is out of range", what, arg, value);
+                None
+            },
+            Err(err) => {
+                println!("Invalid {} {}: {}", what, arg, err);
+                None
+            },
+        }
+    }
+
+    // Counts are decimal, unlike addresses and values
+    fn parse_count(&self, arg: &str, what: &str) -> Option<usize> {
+        self.parse_value(arg, 10, i64::MAX, what).map(|count| count as usize)
+    }
+
+    /// Returns true if execution should resume
+    fn step_cmd(&mut self, cmds: Vec<&str>) -> bool {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            return true;
+        }
+        let count = match self.parse_count(cmds[1], "step count") {
+            Some(num) if num > 0 => num,
+            Some(_) => {
+                println!("Invalid step count {}", cmds[1]);
+                return false;
+            },
+            None => return false,
+        };
+        // The current instruction is executed before the target is checked for the first time
+        self.step_target = Some(StepTarget::Steps(count - 1));
+        self.continuous_run = true;
+        true
+    }
+
+    fn over_cmd(&mut self) {
+        let ni = self.cpu.get_next_instruction();
+        if ni.operation == Operation::Jsr {
+            self.step_target = Some(StepTarget::Over { return_pc: self.cpu.pc.wrapping_add(3), sp: self.cpu.sp });
+            self.continuous_run = true;
+        }
+    }
+
+    /// Returns true if execution should resume
+    fn until_cmd(&mut self, cmds: Vec<&str>) -> bool {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Usage: until <address>");
+            return false;
+        }
+        match self.parse_address_arg(&cmds, 1) {
+            Some(address) => {
+                self.step_target = Some(StepTarget::Until(address));
+                self.continuous_run = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Lists source around the current PC, a label or address, or `file:line`
+    fn list_cmd(&self, cmds: Vec<&str>) {
+        let info = match &self.debug_info {
+            Some(info) => info,
+            None => {
+                println!("No debug info loaded, use dbginfo <file.dbg>");
+                return;
+            },
+        };
+        let location = if cmds.len() > 1 && cmds[1].contains(':') {
+            self.parse_source_location(cmds[1])
+        }
+        else {
+            self.parse_address_arg(&cmds, 1).and_then(|address| {
+                let location = info.location_at(address);
+                if location.is_none() {
+                    println!("No source line for {}", self.format_address(address));
+                }
+                location
+            })
+        };
+        if let Some(location) = location {
+            self.print_source(&location, 5);
+        }
+    }
+
+    fn parse_source_location(&self, arg: &str) -> Option<SourceLocation> {
+        let location = self.debug_info.as_ref().zip(arg.rsplit_once(':')).and_then(|(info, (file, line))| {
+            Some(SourceLocation { file: info.find_file(file)?, line: line.parse().ok()? })
+        });
+        if location.is_none() {
+            println!("Unknown source location {}", arg);
+        }
+        location
+    }
+
+    /// Returns true if execution should resume
+    fn line_step_cmd(&mut self, over_calls: bool) -> bool {
+        if self.debug_info.is_none() {
+            println!("No debug info loaded, use dbginfo <file.dbg>");
+            return false;
+        }
+        let depth = if over_calls { Some(self.call_stack.frames.len()) } else { None };
+        self.step_target = Some(StepTarget::Line { from: self.source_location(self.cpu.pc), depth });
+        self.continuous_run = true;
+        true
+    }
+
+    fn back_cmd(&mut self, cmds: Vec<&str>) {
+        let count: usize = if cmds.len() < 2 || cmds[1].is_empty() {
+            1
+        }
+        else {
+            match self.parse_count(cmds[1], "step count") {
+                Some(num) => num,
+                None => return,
+            }
+        };
+        for _i in 0..count {
+            if !self.step_back() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+        }
+        self.print_cpu_state();
+        self.print_next_instruction(&self.cpu.get_next_instruction());
+    }
+
+    /// Steps backwards until an enabled breakpoint is reached or the history runs out
+    fn reverse_continue(&mut self) {
+        loop {
+            if !self.step_back() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+            if let Some(bp) = self.breakpoints.iter().find(|bp| bp.enabled && bp.kind == BreakpointKind::Execute && bp.address == self.cpu.pc) {
+                println!("Reached breakpoint {} at pos {}", bp.id, self.format_address(self.cpu.pc));
+                break;
+            }
+        }
+        self.print_cpu_state();
+        self.print_next_instruction(&self.cpu.get_next_instruction());
+    }
+
+    fn break_cmd(&mut self, cmds: Vec<&str>, temporary: bool) {
+        // A source line can generate code in several places, e.g. a macro, so it may need several breakpoints
+        let addresses = if cmds.len() > 1 && cmds[1].contains(':') {
+            let location = match self.parse_source_location(cmds[1]) {
+                Some(location) => location,
+                None => return,
+            };
+            let addresses = self.debug_info.as_ref().unwrap().addresses_of(&location).to_vec();
+            if addresses.is_empty() {
+                println!("No code generated for {}", cmds[1]);
+                return;
+            }
+            addresses
+        }
+        else {
+            match self.parse_address_arg(&cmds, 1) {
+                Some(address) => vec![address],
+                None => return,
+            }
+        };
+        // Anything after the address is kept as the message shown when the breakpoint is hit
+        let message = if cmds.len() > 2 { Some(cmds[2..].join(" ")) } else { None };
+        for address in addresses {
+            let id = self.breakpoints.add(address, message.clone(), temporary);
+            println!("{} {} at {}", if temporary {"Temporary breakpoint"} else {"Breakpoint"}, id, self.format_address(address));
+        }
+    }
+
+    fn watch_cmd(&mut self, cmds: Vec<&str>, kind: BreakpointKind) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Usage: {} <address> [length]", cmds[0]);
+            return;
+        }
+        let address = match self.parse_address_arg(&cmds, 1) {
+            Some(address) => address,
+            None => return,
+        };
+        let length: u16 = if cmds.len() < 3 {
+            1
+        }
+        else {
+            match self.parse_value(cmds[2], 16, u16::MAX as i64, "length") {
+                Some(num) => num as u16,
+                None => return,
+            }
+        };
+        let id = self.breakpoints.add_watchpoint(kind, address, length);
+        let name = kind.name();
+        println!("{}{} {} at {}", name[..1].to_uppercase(), &name[1..], id, self.format_address(address));
+    }
+
+    /// set <register> <value>, or set flag <N|V|B|D|I|Z|C> <0|1>
+    fn set_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 3 || cmds[2].is_empty() {
+            println!("Usage: set <a|x|y|sp|sr|pc> <value> or set flag <flag> <0|1>");
+            return;
+        }
+        if cmds[1].eq_ignore_ascii_case("flag") {
+            let flag = match cmds[2].to_uppercase().as_str() {
+                "N" => FLAG_NEGATIVE,
+                "V" => FLAG_OVERFLOW,
+                "B" => FLAG_BREAK,
+                "D" => FLAG_DECIMAL,
+                "I" => FLAG_INTERRUPT,
+                "Z" => FLAG_ZERO,
+                "C" => FLAG_CARRY,
+                _ => {
+                    println!("Unknown flag {}", cmds[2]);
+                    return;
+                },
+            };
+            let value = match cmds.get(3) {
+                Some(&"0") => false,
+                Some(&"1") => true,
+                _ => {
+                    println!("Flag value must be 0 or 1");
+                    return;
+                },
+            };
+            self.cpu.set_flag(value, flag);
+        }
+        else {
+            let value = match self.parse_value(cmds[2], 16, 0xFFFF, "value") {
+                Some(value) => value as u16,
+                None => return,
+            };
+            let register = cmds[1].to_lowercase();
+            if register.eq("pc") {
+                self.cpu.pc = value;
+            }
+            else {
+                if value > 0xFF {
+                    println!("Value {} does not fit in {}", cmds[2], cmds[1]);
+                    return;
+                }
+                match register.as_str() {
+                    "a" => self.cpu.a = value as u8,
+                    "x" => self.cpu.x = value as u8,
+                    "y" => self.cpu.y = value as u8,
+                    "sp" => self.cpu.sp = value as u8,
+                    "sr" | "p" => self.cpu.sr = value as u8,
+                    _ => {
+                        println!("Unknown register {}", cmds[1]);
+                        return;
+                    },
+                }
+            }
+        }
+        self.loop_detector.restart(&self.cpu.get_cpu_state());
+        self.print_cpu_state();
+    }
+
+    /// poke <address> <byte> [byte ...]
+    fn poke_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 3 || cmds[2].is_empty() {
+            println!("Usage: poke <address> <byte> [byte ...]");
+            return;
+        }
+        let address = match self.parse_address_arg(&cmds, 1) {
+            Some(address) => address,
+            None => return,
+        };
+        if let Some(bytes) = self.parse_bytes(&cmds[2..]) {
+            self.write_memory(address, &bytes);
+        }
+    }
+
+    /// fill <start> <end> <byte> [byte ...], repeating the bytes up to and including the end address
+    fn fill_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 4 || cmds[3].is_empty() {
+            println!("Usage: fill <start> <end> <byte> [byte ...]");
+            return;
+        }
+        let (start, end) = match (self.parse_address_arg(&cmds, 1), self.parse_address_arg(&cmds, 2)) {
+            (Some(start), Some(end)) if start <= end => (start, end),
+            (Some(_), Some(_)) => {
+                println!("The end address comes before the start");
+                return;
+            },
+            _ => return,
+        };
+        if let Some(pattern) = self.parse_bytes(&cmds[3..]) {
+            let bytes: Vec<u8> = pattern.iter().copied().cycle().take((end - start) as usize + 1).collect();
+            self.write_memory(start, &bytes);
+        }
+    }
+
+    /// copy <source> <destination> <length>, where overlapping ranges copy like memmove
+    fn copy_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 4 || cmds[3].is_empty() {
+            println!("Usage: copy <source> <destination> <length>");
+            return;
+        }
+        let (source, destination) = match (self.parse_address_arg(&cmds, 1), self.parse_address_arg(&cmds, 2)) {
+            (Some(source), Some(destination)) => (source, destination),
+            _ => return,
+        };
+        let length = match self.parse_value(cmds[3], 16, 0xFFFF, "length") {
+            Some(length) => length as u16,
+            None => return,
+        };
+        let bytes: Vec<u8> = (0..length).map(|offset| self.cpu.read_memory_u8(source.wrapping_add(offset))).collect();
+        self.write_memory(destination, &bytes);
+    }
+
+    /// load <file> <address>, the counterpart of dump
+    fn load_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 3 || cmds[2].is_empty() {
+            println!("Usage: load <file> <address>");
+            return;
+        }
+        let address = match self.parse_address_arg(&cmds, 2) {
+            Some(address) => address,
+            None => return,
+        };
+        let mut data = match read(cmds[1]) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("Could not read {}: {}", cmds[1], err);
+                return;
+            },
+        };
+        let room = self.cpu.memory.len() - address as usize;
+        if data.len() > room {
+            println!("Only the first {} of {} bytes fit in memory", room, data.len());
+            data.truncate(room);
+        }
+        self.write_memory(address, &data);
+        println!("Loaded {} bytes at {}", data.len(), self.format_address(address));
+    }
+
+    /// dump <file> [start] [length], all of memory by default
+    fn dump_cmd(&self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Usage: dump <file> [start] [length]");
+            return;
+        }
+        let start = if cmds.len() < 3 {
+            0
+        }
+        else {
+            match self.parse_address_arg(&cmds, 2) {
+                Some(start) => start,
+                None => return,
+            }
+        };
+        let length = match cmds.get(3) {
+            Some(length) => match self.parse_value(length, 16, 0x10000, "length") {
+                Some(length) => length as usize,
+                None => return,
+            },
+            None => self.cpu.memory.len(),
+        };
+        match self.dump_memory(cmds[1], start, length) {
+            Ok(written) => println!("Dumped {} bytes from {} to {}", written, self.format_address(start), cmds[1]),
+            Err(err) => println!("Could not write {}: {}", cmds[1], err),
+        }
+    }
+
+    /// find <start> <end> <pattern>, where the pattern mixes hex bytes, ?? wildcards and "quoted text"
+    fn find_cmd(&self, cmds: Vec<&str>) {
+        if cmds.len() < 4 || cmds[3].is_empty() {
+            println!("Usage: find <start> <end> <byte|??|\"text\"> ...");
+            return;
+        }
+        let (start, end) = match (self.parse_address_arg(&cmds, 1), self.parse_address_arg(&cmds, 2)) {
+            (Some(start), Some(end)) => (start as usize, end as usize),
+            _ => return,
+        };
+        let pattern = match self.parse_pattern(&cmds[3..].join(" ")) {
+            Some(pattern) if !pattern.is_empty() => pattern,
+            _ => return,
+        };
+        let matches = find_pattern(&self.cpu.memory, start, end, &pattern);
+        for address in matches.iter().take(MAX_LISTED_ADDRESSES) {
+            println!("{}", self.format_address(*address as u16));
+        }
+        if matches.len() > MAX_LISTED_ADDRESSES {
+            println!("...");
+        }
+        println!("{} matches", matches.len());
+    }
+
+    /// cheat snapshot [start end] takes a snapshot of memory, by default all of it.
+    /// cheat changed|unchanged|increased|decreased|== <value>|!= <value> keeps the addresses that changed that way since.
+    fn cheat_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Usage: cheat snapshot [start end], cheat changed|unchanged|increased|decreased|== <value>|!= <value>, cheat list");
+            return;
+        }
+        let filter = match cmds[1] {
+            "snapshot" => {
+                let (start, end) = if cmds.len() < 4 {
+                    (0, 0xFFFF)
+                }
+                else {
+                    match (self.parse_address_arg(&cmds, 2), self.parse_address_arg(&cmds, 3)) {
+                        (Some(start), Some(end)) if start <= end => (start, end),
+                        (Some(_), Some(_)) => {
+                            println!("The end address comes before the start");
+                            return;
+                        },
+                        _ => return,
+                    }
+                };
+                let finder = CheatFinder::new(&self.cpu.memory, start, end);
+                println!("Snapshot taken, {} candidates", finder.candidates.len());
+                self.cheat_finder = Some(finder);
+                return;
+            },
+            "list" => {
+                self.print_cheat_candidates();
+                return;
+            },
+            "changed" => CheatFilter::Changed,
+            "unchanged" => CheatFilter::Unchanged,
+            "increased" => CheatFilter::Increased,
+            "decreased" => CheatFilter::Decreased,
+            "==" | "!=" => {
+                let value = match cmds.get(2).filter(|arg| !arg.is_empty()) {
+                    Some(arg) => match self.parse_value(arg, 16, 0xFF, "byte value") {
+                        Some(value) => value as u8,
+                        None => return,
+                    },
+                    None => {
+                        println!("Usage: cheat {} <byte>", cmds[1]);
+                        return;
+                    },
+                };
+                if cmds[1].eq("==") { CheatFilter::Equal(value) } else { CheatFilter::NotEqual(value) }
+            },
+            _ => {
+                println!("Unknown cheat subcommand {}", cmds[1]);
+                return;
+            },
+        };
+        let count = match &mut self.cheat_finder {
+            Some(finder) => finder.narrow(&self.cpu.memory, &filter),
+            None => {
+                println!("No snapshot taken, use cheat snapshot");
+                return;
+            },
+        };
+        println!("{} candidates left", count);
+        if count <= MAX_LISTED_ADDRESSES {
+            self.print_cheat_candidates();
+        }
+    }
+
+    fn print_cheat_candidates(&self) {
+        let finder = match &self.cheat_finder {
+            Some(finder) => finder,
+            None => {
+                println!("No snapshot taken, use cheat snapshot");
+                return;
+            },
+        };
+        for address in finder.candidates.iter().take(MAX_LISTED_ADDRESSES) {
+            println!("{}: {:02X}", self.format_address(*address), self.cpu.memory[*address as usize]);
+        }
+        if finder.candidates.len() > MAX_LISTED_ADDRESSES {
+            println!("... {} candidates in total", finder.candidates.len());
+        }
+    }
+
+    /// profile on|off starts or pauses recording, profile reset drops what was recorded.
+    /// profile report [rows] lists the routines and addresses using the most cycles, profile export <file> writes collapsed stacks.
+    fn profile_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            match &self.profiler {
+                Some(profiler) => println!("Profiling {}, {} instructions and {} cycles recorded", if profiler.recording {"on"} else {"paused"}, profiler.instructions, profiler.total_cycles),
+                None => println!("Profiling off"),
+            }
+            return;
+        }
+        if cmds[1].eq("on") {
+            match &mut self.profiler {
+                Some(profiler) => profiler.recording = true,
+                None => self.profiler = Some(Profiler::new()),
+            }
+            println!("Profiling on");
+            return;
+        }
+        let profiler = match &mut self.profiler {
+            Some(profiler) => profiler,
+            None => {
+                println!("Nothing recorded, use profile on");
+                return;
+            },
+        };
+        if cmds[1].eq("off") {
+            profiler.recording = false;
+            println!("Profiling paused");
+        }
+        else if cmds[1].eq("reset") {
+            let recording = profiler.recording;
+            *profiler = Profiler::new();
+            profiler.recording = recording;
+        }
+        else if cmds[1].eq("report") {
+            let rows = match cmds.get(2) {
+                None | Some(&"") => Some(DEFAULT_PROFILE_ROWS),
+                Some(rows) => self.parse_count(rows, "row count"),
+            };
+            if let Some(rows) = rows {
+                self.print_profile(rows);
+            }
+        }
+        else if cmds[1].eq("export") && cmds.len() > 2 && !cmds[2].is_empty() {
+            let written = File::create(cmds[2]).and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                let lines = profiler.write_collapsed(&mut writer, |address| self.symbols.name_at(address).map(String::from).unwrap_or_else(|| format!("{:04X}", address)))?;
+                writer.flush()?;
+                Ok(lines)
+            });
+            match written {
+                Ok(lines) => println!("Wrote {} call paths to {}", lines, cmds[2]),
+                Err(err) => println!("Could not write {}: {}", cmds[2], err),
+            }
+        }
+        else {
+            println!("Usage: profile on|off|reset, profile report [rows], profile export <file>");
+        }
+    }
+
+    fn print_profile(&self, rows: usize) {
+        let profiler = match &self.profiler {
+            Some(profiler) => profiler,
+            None => return,
+        };
+        let percent = |cycles: u64| if profiler.total_cycles == 0 { 0.0 } else { cycles as f64 * 100.0 / profiler.total_cycles as f64 };
+        println!("{} instructions, {} cycles", profiler.instructions, profiler.total_cycles);
+
+        let mut routines: Vec<_> = profiler.routines.iter().collect();
+        routines.sort_by(|a, b| b.1.exclusive_cycles.cmp(&a.1.exclusive_cycles).then(a.0.cmp(b.0)));
+        println!("Calls      Inclusive        %   Exclusive        %  Routine");
+        for (target, routine) in routines.iter().take(rows) {
+            println!("{:<10} {:<12} {:>5.1}   {:<12} {:>5.1}  {}", routine.calls, routine.inclusive_cycles, percent(routine.inclusive_cycles), routine.exclusive_cycles, percent(routine.exclusive_cycles), self.format_address(**target));
+        }
+        println!("{:<10} {:<12} {:>5.1}   {:<12} {:>5.1}  top level", "", profiler.total_cycles, 100.0, profiler.top_level_cycles, percent(profiler.top_level_cycles));
+
+        let mut addresses: Vec<usize> = (0..profiler.cycles.len()).filter(|address| profiler.executions[*address] > 0).collect();
+        addresses.sort_by(|a, b| profiler.cycles[*b].cmp(&profiler.cycles[*a]).then(a.cmp(b)));
+        println!("Executions Cycles           %  Instruction");
+        for address in addresses.into_iter().take(rows) {
+            let instruction = self.cpu.get_instruction_at(address as u16);
+            println!("{:<10} {:<12} {:>5.1}  {}", profiler.executions[address], profiler.cycles[address], percent(profiler.cycles[address]), self.disassemble(&instruction, address as u16));
+        }
+    }
+
+    /// coverage on|off starts or pauses recording, coverage reset drops what was recorded, coverage report sums it up.
+    /// coverage export <file> writes every executed address, coverage lcov <file> the covered source lines of the debug info.
+    fn coverage_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            match &self.coverage {
+                Some(coverage) => println!("Coverage {}, {} addresses executed", if coverage.recording {"on"} else {"paused"}, coverage.executed_addresses()),
+                None => println!("Coverage off"),
+            }
+            return;
+        }
+        if cmds[1].eq("on") {
+            match &mut self.coverage {
+                Some(coverage) => coverage.recording = true,
+                None => self.coverage = Some(Coverage::new()),
+            }
+            println!("Coverage on");
+            return;
+        }
+        let coverage = match &mut self.coverage {
+            Some(coverage) => coverage,
+            None => {
+                println!("Nothing recorded, use coverage on");
+                return;
+            },
+        };
+        if cmds[1].eq("off") {
+            coverage.recording = false;
+            println!("Coverage paused");
+        }
+        else if cmds[1].eq("reset") {
+            let recording = coverage.recording;
+            *coverage = Coverage::new();
+            coverage.recording = recording;
+        }
+        else if cmds[1].eq("report") {
+            self.print_coverage();
+        }
+        else if (cmds[1].eq("export") || cmds[1].eq("lcov")) && cmds.len() > 2 && !cmds[2].is_empty() {
+            let coverage = self.coverage.as_ref().expect("checked above");
+            if cmds[1].eq("lcov") && self.debug_info.is_none() {
+                println!("No debug info loaded, use dbginfo <file.dbg>");
+                return;
+            }
+            let written = File::create(cmds[2]).and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                match &self.debug_info {
+                    Some(debug_info) if cmds[1].eq("lcov") => coverage.write_lcov(&mut writer, debug_info, &self.cpu)?,
+                    _ => coverage.write_report(&mut writer, |address| self.disassemble(&self.cpu.get_instruction_at(address), address))?,
+                };
+                writer.flush()
+            });
+            match written {
+                Ok(()) => println!("Wrote coverage to {}", cmds[2]),
+                Err(err) => println!("Could not write {}: {}", cmds[2], err),
+            }
+        }
+        else {
+            println!("Usage: coverage on|off|reset|report, coverage export|lcov <file>");
+        }
+    }
+
+    fn print_coverage(&self) {
+        let coverage = match &self.coverage {
+            Some(coverage) => coverage,
+            None => return,
+        };
+        println!("{} addresses executed", coverage.executed_addresses());
+        let both = coverage.branches.values().filter(|branch| branch.taken > 0 && branch.not_taken > 0).count();
+        println!("{} branches executed, {} of them went both ways", coverage.branches.len(), both);
+        // The branches that went one way only point at untested paths
+        let partial: Vec<_> = coverage.branches.iter().filter(|(_, branch)| branch.taken == 0 || branch.not_taken == 0).collect();
+        for (address, branch) in partial.iter().take(MAX_LISTED_ADDRESSES) {
+            println!("{:<40} {}", self.disassemble(&self.cpu.get_instruction_at(**address), **address), branch.name());
+        }
+        if partial.len() > MAX_LISTED_ADDRESSES {
+            println!("... {} branches went one way only", partial.len());
+        }
+    }
+
+    /// access on|off starts or pauses recording how every byte is accessed, access reset forgets it.
+    /// access export <file> writes the flags of every address, access <address> shows them for one.
+    fn access_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            match &self.access_map {
+                Some(map) => {
+                    println!("Access map {}", if map.recording {"on"} else {"paused"});
+                    for (name, count) in map.counts() {
+                        println!("{:<8} {} bytes", name, count);
+                    }
+                },
+                None => println!("Access map off"),
+            }
+            return;
+        }
+        if cmds[1].eq("on") {
+            match &mut self.access_map {
+                Some(map) => map.recording = true,
+                None => self.access_map = Some(AccessMap::new()),
+            }
+            println!("Access map on");
+            return;
+        }
+        let map = match &mut self.access_map {
+            Some(map) => map,
+            None => {
+                println!("Nothing recorded, use access on");
+                return;
+            },
+        };
+        if cmds[1].eq("off") {
+            map.recording = false;
+            println!("Access map paused");
+        }
+        else if cmds[1].eq("reset") {
+            let recording = map.recording;
+            *map = AccessMap::new();
+            map.recording = recording;
+        }
+        else if cmds[1].eq("export") {
+            if cmds.len() < 3 || cmds[2].is_empty() {
+                println!("Usage: access export <file>");
+                return;
+            }
+            match map.export(cmds[2]) {
+                Ok(()) => println!("Wrote the access map to {}", cmds[2]),
+                Err(err) => println!("Could not write {}: {}", cmds[2], err),
+            }
+        }
+        else if let Some(address) = self.parse_address_arg(&cmds, 1) {
+            let flags = self.access_map.as_ref().map(|map| map.flags[address as usize]).unwrap_or(0);
+            println!("{}: {}", self.format_address(address), describe(flags));
+        }
+    }
+
+    /// disasm [start] [lines] disassembles from the PC by default.
+    /// Bytes the access map saw used as data but never executed are shown as .byte.
+    fn disasm_cmd(&self, cmds: Vec<&str>) {
+        let mut address = match self.parse_address_arg(&cmds, 1) {
+            Some(address) => address,
+            None => return,
+        };
+        let lines = match cmds.get(2) {
+            None | Some(&"") => DEFAULT_DISASSEMBLY_LINES,
+            Some(lines) => match self.parse_count(lines, "line count") {
+                Some(lines) => lines,
+                None => return,
+            },
+        };
+        let is_data = |address: u16| self.access_map.as_ref().is_some_and(|map| map.is_data(address));
+        for _i in 0..lines {
+            // The operand of an instruction at the very end of memory would wrap around
+            let length = if is_data(address) || address > 0xFFFD {
+                // A label starts a new line, so that it stays visible
+                let mut length = 1;
+                while length < DATA_BYTES_PER_LINE && (address as usize + length) <= 0xFFFF {
+                    let next = address + length as u16;
+                    if !is_data(next) || self.symbols.name_at(next).is_some() {
+                        break;
+                    }
+                    length += 1;
+                }
+                println!("{} {}", self.format_address(address), format_data(&self.cpu.memory[address as usize..address as usize + length]));
+                length
+            }
+            else {
+                let instruction = self.cpu.get_instruction_at(address);
+                println!("{}", self.disassemble(&instruction, address));
+                instruction.address_mode.address_size() as usize + 1
+            };
+            match address.checked_add(length as u16) {
+                Some(next) => address = next,
+                None => break,
+            }
+        }
+    }
+
+    /// smc on|break|off reports writes to executed code, stops at them or stops looking, smc reset forgets what ran.
+    /// smc alone lists the instructions that modified code.
+    fn smc_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            self.print_smc_mode();
+            for ((pc, address), count) in self.smc_detector.sites.iter() {
+                println!("{} wrote {} {} time(s)", self.format_address(*pc), self.format_address(*address), count);
+            }
+            return;
+        }
+        match cmds[1] {
+            "on" => self.smc_detector.set_mode(SmcMode::Report),
+            "break" => self.smc_detector.set_mode(SmcMode::Break),
+            "off" => self.smc_detector.set_mode(SmcMode::Off),
+            "reset" => {
+                self.smc_detector.reset();
+                return;
+            },
+            _ => {
+                println!("Usage: smc on|break|off|reset");
+                return;
+            },
+        }
+        self.print_smc_mode();
+    }
+
+    fn print_smc_mode(&self) {
+        match self.smc_detector.mode {
+            SmcMode::Off => println!("Self-modifying code detection off"),
+            SmcMode::Report => println!("Reporting self-modifying code"),
+            SmcMode::Break => println!("Stopping at self-modifying code"),
+        }
+    }
+
+    /// stack check on|break|off reports stack misuse, stops at it or stops looking, stack check reset forgets the reported instructions.
+    /// stack check alone lists the instructions that misused the stack.
+    fn stack_check_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 3 || cmds[2].is_empty() {
+            self.print_stack_check_mode();
+            for (pc, count) in self.stack_checker.sites.iter() {
+                println!("{} {} time(s)", self.disassemble(&self.cpu.get_instruction_at(*pc), *pc), count);
+            }
+            return;
+        }
+        match cmds[2] {
+            "on" => self.stack_checker.mode = StackCheckMode::Report,
+            "break" => self.stack_checker.mode = StackCheckMode::Break,
+            "off" => self.stack_checker.mode = StackCheckMode::Off,
+            "reset" => {
+                self.stack_checker.sites.clear();
+                return;
+            },
+            _ => {
+                println!("Usage: stack check on|break|off|reset");
+                return;
+            },
+        }
+        self.print_stack_check_mode();
+    }
+
+    fn print_stack_check_mode(&self) {
+        match self.stack_checker.mode {
+            StackCheckMode::Off => println!("Stack checks off"),
+            StackCheckMode::Report => println!("Reporting stack misuse"),
+            StackCheckMode::Break => println!("Stopping at stack misuse"),
+        }
+    }
+
+    // Debugger writes trigger watchpoints like the program's writes do, but only report them
+    fn write_memory(&mut self, address: u16, bytes: &[u8]) {
+        if let Some(hit) = self.poke(address, bytes) {
+            println!("Hit watchpoint {}: debugger write to {} {:02X} -> {:02X}", hit.id, self.format_address(hit.address), hit.old_value.unwrap_or(0), self.cpu.memory[hit.address as usize]);
+        }
+    }
+
+    /// Queues the commands of a script file, ahead of anything already queued
+    pub fn source_file(&mut self, filename: &str) {
+        match read_to_string(filename) {
+            Ok(contents) => {
+                let lines: Vec<String> = contents.lines().map(|line| line.to_string()).collect();
+                self.queue_commands(&lines, self.command_depth);
+            },
+            Err(err) => println!("Could not read {}: {}", filename, err),
+        }
+    }
+
+    // Queued commands run in order, before any commands queued earlier
+    fn queue_commands(&mut self, commands: &[String], depth: usize) {
+        for cmd in commands.iter().rev() {
+            let cmd = cmd.trim();
+            if !cmd.is_empty() && !cmd.starts_with('#') {
+                self.pending_commands.push_front((cmd.to_string(), depth));
+            }
+        }
+    }
+
+    // Reads the lines of a define or commands block, up to `end`
+    fn read_block(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(line) = self.next_command() {
+            let line = line.trim();
+            if line.eq("end") {
+                break;
+            }
+            if !line.is_empty() && !line.starts_with('#') {
+                lines.push(line.to_string());
+            }
+        }
+        lines
+    }
+
+    fn define_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Usage: define <name>, followed by the commands and end");
+            return;
+        }
+        let body = self.read_block();
+        println!("Defined {} ({} commands)", cmds[1], body.len());
+        self.macros.insert(cmds[1].to_string(), body);
+    }
+
+    /// Runs a macro, where $arg0 to $arg9 are replaced by its arguments and $argc by their amount
+    fn expand_macro(&mut self, body: &[String], args: &[&str]) {
+        if self.command_depth >= MAX_MACRO_DEPTH {
+            println!("Macros nested more than {} deep, one of them calls itself. Stopped running queued commands", MAX_MACRO_DEPTH);
+            self.pending_commands.clear();
+            return;
+        }
+        let args: Vec<&str> = args.iter().copied().filter(|arg| !arg.is_empty()).collect();
+        let expanded: Vec<String> = body.iter().map(|line| {
+            let line = line.replace("$argc", &args.len().to_string());
+            args.iter().enumerate().rev().fold(line, |line, (index, arg)| line.replace(&format!("$arg{}", index), arg))
+        }).collect();
+        self.queue_commands(&expanded, self.command_depth + 1);
+    }
+
+    /// Sets the commands run when a breakpoint stops execution, by default for the newest breakpoint
+    fn commands_cmd(&mut self, cmds: Vec<&str>) {
+        let id = if cmds.len() < 2 || cmds[1].is_empty() {
+            self.breakpoints.last_id()
+        }
+        else {
+            cmds[1].parse().ok()
+        };
+        let body = self.read_block();
+        match id {
+            Some(id) if self.breakpoints.set_commands(id, body) => {},
+            Some(id) => println!("No breakpoint number {}", id),
+            None => println!("No breakpoints."),
+        }
+    }
+
+    fn info_cmd(&self, cmds: Vec<&str>) {
+        if cmds.len() < 2 {
+            println!("Missing info subcommand");
+            return;
+        }
+        if cmds[1].eq("break") || cmds[1].eq("b") {
+            self.print_breakpoints();
+        }
+        else if cmds[1].eq("macros") {
+            let mut names: Vec<&String> = self.macros.keys().collect();
+            names.sort();
+            for name in names {
+                println!("{}: {}", name, self.macros[name].join("; "));
+            }
+        }
+        else {
+            println!("Unknown info subcommand {}", cmds[1]);
+        }
+    }
+
+    pub fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("No breakpoints.");
+            return;
+        }
+        println!("Num  Type  Enb  Address Hits  What");
+        for bp in self.breakpoints.iter() {
+            match self.symbols.name_at(bp.address) {
+                Some(name) => println!("{} <{}>", bp, name),
+                None => println!("{}", bp),
+            }
+            for command in &bp.commands {
+                println!("        {}", command);
+            }
+        }
+    }
+
+    fn delete_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            self.breakpoints.clear();
+            println!("Deleted all breakpoints");
+            return;
+        }
+        for arg in &cmds[1..] {
+            match arg.parse() {
+                Ok(id) => {
+                    if !self.breakpoints.remove(id) {
+                        println!("No breakpoint number {}", id);
+                    }
+                },
+                Err(_) => println!("Invalid breakpoint number {}", arg),
+            }
+        }
+    }
+
+    fn enable_cmd(&mut self, cmds: Vec<&str>, enabled: bool) {
+        if cmds.len() < 2 || cmds[1].is_empty() {
+            println!("Missing breakpoint number");
+            return;
+        }
+        for arg in &cmds[1..] {
+            match arg.parse() {
+                Ok(id) => {
+                    if !self.breakpoints.set_enabled(id, enabled) {
+                        println!("No breakpoint number {}", id);
+                    }
+                },
+                Err(_) => println!("Invalid breakpoint number {}", arg),
+            }
+        }
+    }
+
+    fn ignore_cmd(&mut self, cmds: Vec<&str>) {
+        if cmds.len() < 3 {
+            println!("Usage: ignore <breakpoint number> <count>");
+            return;
+        }
+        let id: usize = match cmds[1].parse() {
+            Ok(num) => num,
+            Err(_) => {
+                println!("Invalid breakpoint number {}", cmds[1]);
+                return;
+            },
+        };
+        let count = match self.parse_count(cmds[2], "count") {
+            Some(num) => num,
+            None => return,
+        };
+        if self.breakpoints.set_ignore_count(id, count) {
+            println!("Will ignore next {} crossings of breakpoint {}", count, id);
+        }
+        else {
+            println!("No breakpoint number {}", id);
+        }
+    }
+
+    // Byte values for poke and fill, in hex like addresses
+    fn parse_bytes(&self, args: &[&str]) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for arg in args.iter().filter(|arg| !arg.is_empty()) {
+            bytes.push(self.parse_value(arg, 16, 0xFF, "byte value")? as u8);
+        }
+        Some(bytes)
+    }
+
+    // Search patterns for find, None stands for a wildcard byte
+    fn parse_pattern(&self, text: &str) -> Option<Vec<Option<u8>>> {
+        let mut pattern = Vec::new();
+        let mut rest = text.trim();
+        while !rest.is_empty() {
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let end = match quoted.find('"') {
+                    Some(end) => end,
+                    None => {
+                        println!("Missing closing quote in {}", text);
+                        return None;
+                    },
+                };
+                pattern.extend(quoted[..end].bytes().map(Some));
+                rest = quoted[end + 1..].trim_start();
+            }
+            else {
+                let (token, remainder) = rest.split_once(' ').unwrap_or((rest, ""));
+                if token.eq("??") {
+                    pattern.push(None);
+                }
+                else {
+                    pattern.push(Some(self.parse_bytes(&[token])?[0]));
+                }
+                rest = remainder.trim_start();
+            }
+        }
+        Some(pattern)
+    }
+}
+
+// Addresses where the pattern matches, where matches have to lie completely inside the inclusive range
+fn find_pattern(memory: &[u8], start: usize, end: usize, pattern: &[Option<u8>]) -> Vec<usize> {
+    match (end + 1).checked_sub(pattern.len()) {
+        Some(last) if last >= start => (start..=last).filter(|address| {
+            pattern.iter().enumerate().all(|(offset, byte)| byte.is_none_or(|byte| memory[address + offset] == byte))
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Splits a trailing format name off the arguments of mem and mem_dec
+fn split_memory_format<'a>(cmds: &'a [&'a str]) -> (&'a [&'a str], MemoryFormat) {
+    match cmds.last().and_then(|arg| MemoryFormat::from_name(arg)) {
+        Some(format) if cmds.len() > 1 => (&cmds[..cmds.len() - 1], format),
+        _ => (cmds, MemoryFormat::Hex),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use strum::IntoEnumIterator;
+
+    use crate::breakpoint::BreakpointKind;
+    use super::{find_pattern, Command, CpuRunner, StepTarget, COMMANDS, MAX_MACRO_DEPTH};
+
+    // 0400 JSR $0410, 0403 NOP, 0404 JMP $0404, 0410 JSR $0420, 0413 RTS, 0420 INX, 0421 RTS
+    fn runner_with_calls() -> CpuRunner {
+        let mut runner = CpuRunner::new();
+        for (address, bytes) in [(0x0400, &[0x20, 0x10, 0x04, 0xEA, 0x4C, 0x04, 0x04][..]), (0x0410, &[0x20, 0x20, 0x04, 0x60]), (0x0420, &[0xE8, 0x60])] {
+            runner.cpu.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        runner.cpu.pc = 0x0400;
+        runner
+    }
+
+
+    // Executes like a continuous run does until the target is reached, returning how many instructions ran
+    fn run_to(runner: &mut CpuRunner, target: StepTarget) -> usize {
+        runner.step_target = Some(target);
+        for executed in 1..1000 {
+            runner.step();
+            if runner.reached_step_target() {
+                return executed;
+            }
+        }
+        panic!("never reached the step target");
+    }
+
+    #[test]
+    fn counted_steps_stop_after_the_count() {
+        let mut runner = runner_with_calls();
+        assert_eq!(run_to(&mut runner, StepTarget::Steps(2)), 3);
+        assert_eq!(runner.cpu.pc, 0x0421);
+    }
+
+    #[test]
+    fn over_runs_the_whole_call() {
+        let mut runner = runner_with_calls();
+        let target = StepTarget::Over { return_pc: 0x0403, sp: runner.cpu.sp };
+        assert_eq!(run_to(&mut runner, target), 5);
+        assert_eq!((runner.cpu.pc, runner.cpu.sp), (0x0403, 0xFF));
+    }
+
+    #[test]
+    fn finish_leaves_only_the_current_frame() {
+        let mut runner = runner_with_calls();
+        run_to(&mut runner, StepTarget::Until(0x0420));
+        let target = StepTarget::Finish { sp: runner.cpu.sp };
+        assert_eq!(run_to(&mut runner, target), 2);
+        assert_eq!(runner.cpu.pc, 0x0413);
+    }
+
+    #[test]
+    fn until_stops_at_the_address() {
+        let mut runner = runner_with_calls();
+        assert_eq!(run_to(&mut runner, StepTarget::Until(0x0404)), 6);
+        assert_eq!(runner.cpu.x, 1);
+    }
+
+    // 0430 LDA #$11, 0432 STA $0200, 0435 INC $0200, 0438 PHA
+    fn runner_with_writes() -> CpuRunner {
+        let mut runner = CpuRunner::new();
+        runner.cpu.memory[0x0430..0x0439].copy_from_slice(&[0xA9, 0x11, 0x8D, 0x00, 0x02, 0xEE, 0x00, 0x02, 0x48]);
+        runner.cpu.pc = 0x0430;
+        for _i in 0..4 {
+            runner.step();
+        }
+        assert_eq!((runner.cpu.memory[0x0200], runner.cpu.memory[0x01FF], runner.cpu.sp), (0x12, 0x11, 0xFE));
+        runner
+    }
+
+    #[test]
+    fn stepping_back_undoes_registers_and_memory() {
+        let mut runner = runner_with_writes();
+        assert!(runner.step_back());
+        assert_eq!((runner.cpu.pc, runner.cpu.sp, runner.cpu.memory[0x01FF]), (0x0438, 0xFF, 0x00));
+        assert!(runner.step_back());
+        assert_eq!(runner.cpu.memory[0x0200], 0x11);
+        assert!(runner.step_back());
+        assert!(runner.step_back());
+        assert_eq!((runner.cpu.pc, runner.cpu.a, runner.cpu.memory[0x0200], runner.op_count), (0x0430, 0x00, 0x00, 0));
+        assert!(!runner.step_back());
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_a_breakpoint() {
+        let mut runner = runner_with_writes();
+        runner.breakpoints.add(0x0435, None, false);
+        runner.reverse_continue();
+        assert_eq!((runner.cpu.pc, runner.cpu.memory[0x0200], runner.op_count), (0x0435, 0x11, 2));
+        runner.breakpoints.clear();
+        runner.reverse_continue();
+        assert_eq!((runner.cpu.pc, runner.op_count), (0x0430, 0));
+    }
+
+    #[test]
+    fn depth_zero_keeps_no_history() {
+        let mut runner = runner_with_calls();
+        runner.set_history_depth(0);
+        run_to(&mut runner, StepTarget::Until(0x0420));
+        assert_eq!(runner.call_stack.frames.len(), 2);
+        let target = runner.finish_target();
+        assert_eq!(run_to(&mut runner, target), 2);
+        assert_eq!(runner.cpu.pc, 0x0413);
+        assert!(runner.cpu.write_journal.is_empty() && runner.instruction_history.is_empty());
+        assert!(!runner.step_back());
+
+        // 0430 LDA #$11, 0432 STA $0200, 0435 JMP $0435
+        runner.cpu.memory[0x0430..0x0438].copy_from_slice(&[0xA9, 0x11, 0x8D, 0x00, 0x02, 0x4C, 0x35, 0x04]);
+        runner.cpu.pc = 0x0430;
+        runner.breakpoints.add_watchpoint(BreakpointKind::Write, 0x0200, 1);
+        runner.step();
+        runner.step();
+        assert_eq!(runner.watch_hit.as_ref().map(|hit| hit.old_value), Some(Some(0x00)));
+        runner.step();
+        runner.step();
+        assert_eq!(runner.loop_hit.as_ref().map(|hit| (hit.pc, hit.length)), Some((0x0435, 1)));
+    }
+
+    fn queue(runner: &mut CpuRunner, commands: &[&str]) {
+        runner.pending_commands.extend(commands.iter().map(|cmd| (cmd.to_string(), 0)));
+        runner.batch_mode = true;
+    }
+
+    #[test]
+    fn macros_substitute_their_arguments() {
+        let mut runner = CpuRunner::new();
+        queue(&mut runner, &["define bb", "break $arg0", "tbreak $arg1", "# comment", "end", "bb 0410 0420 extra"]);
+        assert!(runner.handle_input());
+        assert_eq!(runner.macros["bb"], ["break $arg0", "tbreak $arg1"]);
+        let breakpoints: Vec<(u16, bool)> = runner.breakpoints.iter().map(|bp| (bp.address, bp.temporary)).collect();
+        assert_eq!(breakpoints, [(0x0410, false), (0x0420, true)]);
+
+        runner.expand_macro(&["x $argc $arg1$arg0 $arg2".to_string()], &["a", "", "b"]);
+        assert_eq!(runner.pending_commands, [("x 2 ba $arg2".to_string(), 1)]);
+    }
+
+    #[test]
+    fn macros_calling_themselves_stop_at_the_limit() {
+        let mut runner = CpuRunner::new();
+        queue(&mut runner, &["define aa", "tbreak 0400", "bb", "end", "define bb", "aa", "end", "aa", "break 0410"]);
+        assert!(runner.handle_input());
+        // Every expansion before the limit ran, and nothing queued after the macro did
+        assert_eq!(runner.breakpoints.iter().count(), MAX_MACRO_DEPTH / 2);
+        assert!(runner.breakpoints.iter().all(|bp| bp.address == 0x0400));
+        assert!(runner.pending_commands.is_empty());
+    }
+
+    #[test]
+    fn scripts_run_before_commands_queued_earlier() {
+        let mut runner = CpuRunner::new();
+        queue(&mut runner, &["queued"]);
+        let filename = std::env::temp_dir().join(format!("cpu_runner_test_{}.txt", std::process::id()));
+        std::fs::write(&filename, "# setup\nbreak 0410\n\n  tbreak 0420  \n").expect("temporary script");
+        runner.source_file(filename.to_str().unwrap());
+        std::fs::remove_file(&filename).ok();
+        let queued: Vec<&str> = runner.pending_commands.iter().map(|(cmd, _)| cmd.as_str()).collect();
+        assert_eq!(queued, ["break 0410", "tbreak 0420", "queued"]);
+    }
+
+    #[test]
+    fn breakpoint_commands_run_when_it_stops() {
+        let mut runner = runner_with_calls();
+        queue(&mut runner, &["break 0420", "commands", "delete 1", "tbreak 0403", "end", "c"]);
+        runner.start_run();
+        assert_eq!(runner.cpu.pc, 0x0420);
+        let breakpoints: Vec<(u16, bool)> = runner.breakpoints.iter().map(|bp| (bp.address, bp.temporary)).collect();
+        assert_eq!(breakpoints, [(0x0403, true)]);
+    }
+
+    #[test]
+    fn loops_are_found_right_after_the_debugger_writes() {
+        let mut runner = runner_with_calls();
+        runner.cpu.pc = 0x0404;
+        runner.poke(0x0200, &[0x01]);
+        runner.step();
+        assert!(runner.loop_hit.is_none());
+        runner.step();
+        assert_eq!(runner.loop_hit.as_ref().map(|hit| hit.pc), Some(0x0404));
+    }
+
+    #[test]
+    fn breakpoints_still_hit_when_a_loop_stops_the_run() {
+        let mut runner = runner_with_calls();
+        runner.cpu.pc = 0x0404;
+        runner.poke(0x0200, &[0x01]);
+        let id = runner.breakpoints.add(0x0404, None, false);
+        runner.breakpoints.set_ignore_count(id, 2);
+        runner.breakpoints.set_commands(id, vec!["set x 5".to_string()]);
+        queue(&mut runner, &["c"]);
+        runner.start_run();
+        assert_eq!((runner.cpu.x, runner.breakpoints.iter().next().unwrap().hit_count), (5, 3));
+    }
+
+    #[test]
+    fn registers_and_flags_can_be_set() {
+        let mut runner = CpuRunner::new();
+        for cmd in ["set a 3F", "set x 100", "set pc 0410", "set SR 80", "set flag c 1", "set flag q 1", "set flag z 2"] {
+            runner.execute_command(cmd);
+        }
+        assert_eq!((runner.cpu.a, runner.cpu.x, runner.cpu.pc, runner.cpu.sr), (0x3F, 0x00, 0x0410, 0x81));
+    }
+
+    #[test]
+    fn memory_can_be_poked_filled_copied_and_loaded() {
+        let mut runner = CpuRunner::new();
+        runner.execute_command("poke 0200 01 02 03");
+        runner.execute_command("poke 0210 01 123");
+        runner.execute_command("fill 0300 0306 AA 55");
+        runner.execute_command("fill 0310 030F 00");
+        // Overlapping copies move the bytes as they were before the copy
+        runner.execute_command("copy 0200 0201 3");
+        assert_eq!(runner.cpu.memory[0x0200..0x0205], [0x01, 0x01, 0x02, 0x03, 0x00]);
+        assert_eq!(runner.cpu.memory[0x0210], 0x00);
+        assert_eq!(runner.cpu.memory[0x0300..0x0308], [0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x00]);
+        assert_eq!(runner.cpu.memory[0x030F], 0x00);
+
+        let filename = std::env::temp_dir().join(format!("cpu_runner_test_{}.bin", std::process::id()));
+        std::fs::write(&filename, [0x11, 0x22, 0x33]).expect("temporary file");
+        runner.execute_command(&format!("load {} FFFE", filename.display()));
+        std::fs::remove_file(&filename).ok();
+        assert_eq!((runner.cpu.memory[0xFFFE], runner.cpu.memory[0xFFFF], runner.cpu.memory[0x0000]), (0x11, 0x22, 0x00));
+    }
+
+    #[test]
+    fn dump_and_watch_lengths_are_hex() {
+        let mut runner = CpuRunner::new();
+        runner.cpu.memory[0x0200..0x0212].fill(0x42);
+        runner.execute_command("dump");
+        let filename = std::env::temp_dir().join(format!("cpu_runner_dump_test_{}.bin", std::process::id()));
+        runner.execute_command(&format!("dump {} 01FF 12", filename.display()));
+        let dumped = std::fs::read(&filename).expect("dumped file");
+        std::fs::remove_file(&filename).ok();
+        assert_eq!((dumped.len(), dumped[0], dumped[0x11]), (0x12, 0x00, 0x42));
+
+        runner.execute_command("watch 0200 10");
+        assert_eq!(runner.breakpoints.iter().map(|bp| bp.length).collect::<Vec<_>>(), [0x10]);
+    }
+
+    #[test]
+    fn debugger_writes_cannot_be_stepped_back() {
+        let mut runner = runner_with_writes();
+        runner.execute_command("poke 0200 99");
+        assert!(!runner.step_back());
+        assert_eq!(runner.cpu.memory[0x0200], 0x99);
+    }
+
+    #[test]
+    fn patterns_mix_bytes_wildcards_and_text() {
+        let runner = CpuRunner::new();
+        assert_eq!(runner.parse_pattern("A9 ?? 8d"), Some(vec![Some(0xA9), None, Some(0x8D)]));
+        assert_eq!(runner.parse_pattern("\"HI there\" 00"), Some(b"HI there\0".iter().copied().map(Some).collect()));
+        assert_eq!(runner.parse_pattern("01 \"a\"\"b\""), Some(vec![Some(0x01), Some(b'a'), Some(b'b')]));
+        assert_eq!(runner.parse_pattern("\"open"), None);
+        assert_eq!(runner.parse_pattern("100"), None);
+    }
+
+    #[test]
+    fn find_matches_inside_the_range() {
+        let runner = CpuRunner::new();
+        let mut memory = vec![0u8; 0x10000];
+        memory[0x0200..0x0208].copy_from_slice(&[0xA9, 0x01, 0x8D, 0xA9, 0x02, 0x8D, 0xA9, 0x03]);
+        let pattern = runner.parse_pattern("A9 ?? 8D").unwrap();
+        assert_eq!(find_pattern(&memory, 0x0200, 0x0207, &pattern), [0x0200, 0x0203]);
+        // The match at 0206 would run past the end, the one at 0200 starts before the start
+        assert_eq!(find_pattern(&memory, 0x0201, 0x0207, &pattern), [0x0203]);
+        assert!(find_pattern(&memory, 0x0200, 0x0201, &pattern).is_empty());
+        memory[0xFFFD..].copy_from_slice(b"END");
+        assert_eq!(find_pattern(&memory, 0x0000, 0xFFFF, &runner.parse_pattern("\"END\"").unwrap()), [0xFFFD]);
+    }
+
+    #[test]
+    fn cheat_finder_narrows_to_the_changed_value() {
+        let mut runner = CpuRunner::new();
+        runner.execute_command("cheat snapshot 0200 020F");
+        runner.execute_command("poke 0203 05");
+        runner.execute_command("poke 0208 07");
+        runner.execute_command("cheat changed");
+        assert_eq!(runner.cheat_finder.as_ref().unwrap().candidates, [0x0203, 0x0208]);
+        runner.execute_command("poke 0203 04");
+        runner.execute_command("cheat decreased");
+        assert_eq!(runner.cheat_finder.as_ref().unwrap().candidates, [0x0203]);
+        runner.execute_command("cheat != 04");
+        assert!(runner.cheat_finder.as_ref().unwrap().candidates.is_empty());
+    }
+
+    #[test]
+    fn interrupts_stop_a_continuous_run() {
+        let mut runner = CpuRunner::new();
+        // 0400 INX, 0401 JMP $0400 never repeats a state within the loop detector's reach
+        runner.cpu.memory[0x0400..0x0404].copy_from_slice(&[0xE8, 0x4C, 0x00, 0x04]);
+        runner.cpu.pc = 0x0400;
+        queue(&mut runner, &["c"]);
+        let interrupt_requested = runner.interrupt_requested.clone();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            interrupt_requested.store(true, Ordering::Relaxed);
+        });
+        runner.start_run();
+        interrupter.join().unwrap();
+        assert!(runner.op_count > 0 && runner.cpu.pc < 0x0404);
+    }
+
+    #[test]
+    fn interrupts_at_the_prompt_are_dropped() {
+        let mut runner = runner_with_calls();
+        runner.interrupt_requested.store(true, Ordering::Relaxed);
+        queue(&mut runner, &["until 0404"]);
+        runner.start_run();
+        assert_eq!(runner.cpu.pc, 0x0404);
+    }
+
+    // Dispatch and tab completion both look commands up in COMMANDS, so each one needs a name there
+    #[test]
+    fn every_command_has_a_name() {
+        for command in Command::iter() {
+            assert!(COMMANDS.iter().any(|(_, named)| *named == command), "{:?} has no name in COMMANDS", command);
+        }
+        let mut names: Vec<&str> = COMMANDS.iter().map(|(name, _)| *name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), COMMANDS.len());
+
+        let mut runner = CpuRunner::new();
+        runner.execute_command("b 0410");
+        runner.execute_command("tbreak 0420");
+        assert_eq!(runner.breakpoints.iter().count(), 2);
+    }
+}
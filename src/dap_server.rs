@@ -0,0 +1,695 @@
+// Debug Adapter Protocol server, so editors like VS Code can debug programs running on the emulator.
+// Requests are read on their own thread, which lets a running program be paused between batches of instructions.
+// Launch arguments, all optional:
+//   program       binary loaded at loadAddress (default 0), started at startAddress (default the reset vector)
+//   symbols       label file in any format symbols.rs reads
+//   debugInfo     ld65 debug file, needed for source breakpoints and line stepping
+//   stopOnEntry   stop before the first instruction instead of running
+// Attach takes the same arguments but keeps the program already in memory, and stops on entry unless told otherwise.
+// Addresses in requests may be numbers, labels or hex strings such as "0x0400" or "$0400".
+
+use std::{io::{self, BufRead, BufReader, Write}, net::TcpListener, path::Path, fs::read, collections::HashMap, sync::mpsc::{self, Receiver, Sender, TryRecvError}, thread};
+
+use serde_json::{json, Value};
+
+use crate::{cpu_runner::{CpuRunner, StepTarget}, cpu_helpers::Operation, breakpoint::BreakpointKind, debug_info::{DebugInfo, SourceLocation}, disassembler::format_instruction, smc_detector::SmcMode, stack_checker::StackCheckMode};
+
+const POLL_INTERVAL: usize = 1024;     // Instructions executed between checks for new requests while running
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+const FLAG_NAMES: [&str; 8] = ["N", "V", "-", "B", "D", "I", "Z", "C"];
+const MAX_LINE_SEARCH: usize = 50;     // How far past a line without code a source breakpoint may move
+
+pub enum Transport {
+    Stdio,
+    Tcp(u16),
+}
+
+pub struct DapServer<'a> {
+    runner: &'a mut CpuRunner,
+    output: Box<dyn Write>,
+    requests: Receiver<Value>,
+    seq: u64,
+    running: bool,
+    stop_on_entry: bool,
+    pending_stop: Option<(&'static str, Option<String>)>,  // Stopped event to send once the current response is out
+    source_breakpoints: HashMap<usize, Vec<usize>>,         // Source file -> ids in the runner's breakpoint list
+    function_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+    memory_events: bool,    // The client reloads memory and disassembly views when told a range changed
+}
+
+/// Serves a single client until it disconnects
+pub fn serve(runner: &mut CpuRunner, transport: Transport) -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    let output: Box<dyn Write> = match transport {
+        Transport::Stdio => {
+            thread::spawn(move || read_messages(BufReader::new(io::stdin()), sender));
+            Box::new(io::stdout())
+        },
+        Transport::Tcp(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            println!("Waiting for a debug adapter client on port {}", port);
+            let (stream, address) = listener.accept()?;
+            println!("Debug adapter client connected from {}", address);
+            let reader = BufReader::new(stream.try_clone()?);
+            thread::spawn(move || read_messages(reader, sender));
+            Box::new(stream)
+        },
+    };
+    // Stepping with continuous_run set keeps call stack warnings off stdout, which may be the protocol stream
+    runner.continuous_run = true;
+    let mut server = DapServer {
+        runner, output, requests, seq: 0, running: false, stop_on_entry: false, pending_stop: None,
+        source_breakpoints: HashMap::new(), function_breakpoints: Vec::new(), instruction_breakpoints: Vec::new(),
+        memory_events: false,
+    };
+    server.run()
+}
+
+impl DapServer<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let request = if self.running {
+                match self.requests.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => {
+                        self.run_batch()?;
+                        continue;
+                    },
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+            else {
+                match self.requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => break,
+                }
+            };
+            if !self.handle_request(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns false once the session is over
+    fn handle_request(&mut self, request: &Value) -> io::Result<bool> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or("");
+        let result = match command {
+            "initialize" => {
+                self.memory_events = args["supportsMemoryEvent"].as_bool().unwrap_or(false);
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsStepBack": true,
+                    "supportsSteppingGranularity": true,
+                    "supportsSetVariable": true,
+                    "supportsEvaluateForHovers": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsWriteMemoryRequest": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsTerminateRequest": true,
+                }))
+            },
+            "launch" | "attach" => self.launch(args, command == "launch"),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.pending_stop = Some(("entry", None));
+                }
+                else {
+                    self.running = true;
+                }
+                Ok(json!({}))
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace" => Ok(self.stack_trace(args)),
+            "scopes" => Ok(json!({ "scopes": [{ "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false }] })),
+            "variables" => Ok(self.variables(args)),
+            "setVariable" => self.set_variable(args),
+            "continue" => {
+                self.running = true;
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" | "stepIn" | "stepOut" => {
+                self.start_step(command, args);
+                Ok(json!({}))
+            },
+            "stepBack" | "reverseContinue" => {
+                self.pending_stop = Some(self.reverse(command == "reverseContinue"));
+                Ok(json!({}))
+            },
+            "pause" => {
+                if self.running {
+                    self.pending_stop = Some(("pause", None));
+                }
+                Ok(json!({}))
+            },
+            "readMemory" => self.read_memory(args),
+            "writeMemory" => self.write_memory(args),
+            "disassemble" => self.disassemble(args),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            },
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+
+        let success = result.is_ok();
+        self.respond(request, result)?;
+        if success && (command == "launch" || command == "attach") {
+            self.event("initialized", json!({}))?;
+        }
+        if let Some((reason, description)) = self.pending_stop.take() {
+            self.stopped(reason, description)?;
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value, load_program: bool) -> Result<Value, String> {
+        if let Some(filename) = args["debugInfo"].as_str() {
+            let info = DebugInfo::load_file(filename).map_err(|err| err.to_string())?;
+            let missing: Vec<&str> = info.files.values().filter(|file| file.path.is_none()).map(|file| file.name.as_str()).collect();
+            if !missing.is_empty() {
+                self.output(&format!("Could not find the source of {}", missing.join(", ")));
+            }
+            self.runner.debug_info = Some(info);
+            self.load_symbols(filename)?;
+        }
+        if let Some(filename) = args["symbols"].as_str() {
+            self.load_symbols(filename)?;
+        }
+        if let (true, Some(program)) = (load_program, args["program"].as_str()) {
+            let data = read(program).map_err(|err| format!("Could not read {}: {}", program, err))?;
+            let load_address = match args.get("loadAddress") {
+                Some(value) => self.address_arg(value).ok_or("Invalid loadAddress")? as usize,
+                None => 0,
+            };
+            if load_address + data.len() > self.runner.cpu.memory.len() {
+                return Err(format!("{} does not fit in memory at {:04X}", program, load_address));
+            }
+            self.runner.cpu.memory[load_address..load_address + data.len()].copy_from_slice(&data);
+            self.runner.cpu.pc = self.runner.cpu.read_memory_u16(0xFFFC);
+        }
+        if let Some(value) = args.get("startAddress") {
+            self.runner.cpu.pc = self.address_arg(value).ok_or("Invalid startAddress")?;
+        }
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(!load_program);
+        Ok(json!({}))
+    }
+
+    fn load_symbols(&mut self, filename: &str) -> Result<(), String> {
+        let count = self.runner.symbols.load_file(filename).map_err(|err| err.to_string())?;
+        self.output(&format!("Loaded {} symbols from {}", count, filename));
+        Ok(())
+    }
+
+    // Executes up to POLL_INTERVAL instructions, stopping at breakpoints, watchpoints and step targets
+    fn run_batch(&mut self) -> io::Result<()> {
+        for _i in 0..POLL_INTERVAL {
+            self.runner.step();
+            // Report mode only logs a finding to the debug console, break mode stops on it below
+            if let Some(hit) = self.runner.smc_hit.take_if(|_| self.runner.smc_detector.mode == SmcMode::Report) {
+                let description = self.runner.describe_smc_hit(&hit);
+                self.output(&description);
+            }
+            if let Some(issue) = self.runner.stack_issue.take_if(|_| self.runner.stack_checker.mode == StackCheckMode::Report) {
+                let description = self.runner.describe_stack_issue(&issue);
+                self.output(&description);
+            }
+            let stop = if let Some(hit) = self.runner.watch_hit.take() {
+                Some(("data breakpoint", Some(format!("Watchpoint {} at {:04X}", hit.id, hit.address))))
+            }
+            else if let Some(hit) = self.runner.smc_hit.take() {
+                Some(("data breakpoint", Some(self.runner.describe_smc_hit(&hit))))
+            }
+            else if let Some(issue) = self.runner.stack_issue.take() {
+                Some(("exception", Some(self.runner.describe_stack_issue(&issue))))
+            }
+            else if let Some(bp) = self.runner.breakpoints.hit(self.runner.cpu.pc) {
+                Some(("breakpoint", bp.message))
+            }
+            else if self.runner.reached_step_target() {
+                Some(("step", None))
+            }
+            else if let Some(hit) = self.runner.loop_hit.take() {
+                let description = match hit.trap {
+                    Some((address, kind)) => format!("Reached {} trap at {:04X}", kind.name(), address),
+                    None => format!("Found loop at {:04X}, {} instructions long", hit.pc, hit.length),
+                };
+                Some(("pause", Some(description)))
+            }
+            else {
+                None
+            };
+            if let Some((reason, description)) = stop {
+                return self.stopped(reason, description);
+            }
+        }
+        Ok(())
+    }
+
+    // Source stepping needs line information for the current PC, anything else steps single instructions
+    fn start_step(&mut self, command: &str, args: &Value) {
+        let pc = self.runner.cpu.pc;
+        let from = self.runner.source_location(pc);
+        let by_instruction = args["granularity"] == "instruction" || from.is_none();
+        let target = match command {
+            "stepOut" => self.runner.finish_target(),
+            "next" if by_instruction && self.runner.cpu.get_next_instruction().operation == Operation::Jsr => {
+                StepTarget::Over { return_pc: pc.wrapping_add(3), sp: self.runner.cpu.sp }
+            },
+            _ if by_instruction => StepTarget::Steps(0),
+            "next" => StepTarget::Line { from, depth: Some(self.runner.call_stack.frames.len()) },
+            _ => StepTarget::Line { from, depth: None },
+        };
+        self.runner.step_target = Some(target);
+        self.running = true;
+    }
+
+    // Steps back one instruction, or to the previous execution breakpoint for a reverse continue
+    fn reverse(&mut self, to_breakpoint: bool) -> (&'static str, Option<String>) {
+        loop {
+            if !self.runner.step_back() {
+                return ("step", Some("Reached the start of the recorded history".to_string()));
+            }
+            if !to_breakpoint {
+                return ("step", None);
+            }
+            let pc = self.runner.cpu.pc;
+            if self.runner.breakpoints.iter().any(|bp| bp.enabled && bp.kind == BreakpointKind::Execute && bp.address == pc) {
+                return ("breakpoint", None);
+            }
+        }
+    }
+
+    /// Replaces the breakpoints of one source file
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let lines: Vec<usize> = args["breakpoints"].as_array().into_iter().flatten().filter_map(|bp| bp["line"].as_u64()).map(|line| line as usize).collect();
+        let source = &args["source"];
+        let name = source["path"].as_str().or(source["name"].as_str()).unwrap_or("");
+        let info = match &self.runner.debug_info {
+            Some(info) => info,
+            None => return unverified(&lines, "No debug info loaded"),
+        };
+        let file = match info.find_file(name) {
+            Some(file) => file,
+            None => return unverified(&lines, "No debug info for this file"),
+        };
+
+        // Lines without code, e.g. comments, get the breakpoint on the next line that has some
+        let locations: Vec<Option<(SourceLocation, Vec<u16>)>> = lines.iter().map(|line| {
+            (*line..line + MAX_LINE_SEARCH)
+                .map(|line| SourceLocation { file, line })
+                .find(|location| !info.addresses_of(location).is_empty())
+                .map(|location| (location, info.addresses_of(&location).to_vec()))
+        }).collect();
+
+        for id in self.source_breakpoints.remove(&file).unwrap_or_default() {
+            self.runner.breakpoints.remove(id);
+        }
+        let mut ids = Vec::new();
+        let mut breakpoints = Vec::new();
+        for (line, location) in lines.iter().zip(locations) {
+            match location {
+                Some((location, addresses)) => {
+                    let first_id = ids.len();
+                    for address in addresses {
+                        ids.push(self.runner.breakpoints.add(address, None, false));
+                    }
+                    breakpoints.push(json!({ "id": ids[first_id], "verified": true, "line": location.line }));
+                },
+                None => breakpoints.push(json!({ "verified": false, "line": line, "message": "No code generated for this line" })),
+            }
+        }
+        self.source_breakpoints.insert(file, ids);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value) -> Value {
+        let addresses: Vec<Option<u16>> = args["breakpoints"].as_array().into_iter().flatten().map(|bp| self.address_arg(&bp["name"])).collect();
+        let breakpoints = self.replace_breakpoints(addresses, true);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        let addresses: Vec<Option<u16>> = args["breakpoints"].as_array().into_iter().flatten().map(|bp| {
+            let offset = bp["offset"].as_i64().unwrap_or(0) as u16;
+            self.address_arg(&bp["instructionReference"]).map(|address| address.wrapping_add(offset))
+        }).collect();
+        let breakpoints = self.replace_breakpoints(addresses, false);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // Function and instruction breakpoints are always sent as a complete list
+    fn replace_breakpoints(&mut self, addresses: Vec<Option<u16>>, functions: bool) -> Vec<Value> {
+        let list = if functions { &mut self.function_breakpoints } else { &mut self.instruction_breakpoints };
+        for id in list.drain(..) {
+            self.runner.breakpoints.remove(id);
+        }
+        addresses.into_iter().map(|address| match address {
+            Some(address) => {
+                let id = self.runner.breakpoints.add(address, None, false);
+                list.push(id);
+                json!({ "id": id, "verified": true, "instructionReference": format!("0x{:04X}", address) })
+            },
+            None => json!({ "verified": false, "message": "Unknown address" }),
+        }).collect()
+    }
+
+    // The innermost frame is at the PC, every outer one at the instruction that made the call
+    fn stack_trace(&self, args: &Value) -> Value {
+        let frames = &self.runner.call_stack.frames;
+        let mut locations = vec![(self.runner.cpu.pc, frames.last().map(|frame| frame.target))];
+        for (index, frame) in frames.iter().enumerate().rev() {
+            locations.push((frame.caller_pc, index.checked_sub(1).map(|outer| frames[outer].target)));
+        }
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => locations.len(),
+        };
+        let stack_frames: Vec<Value> = locations.iter().enumerate().skip(start).take(levels)
+            .map(|(id, (address, routine))| self.stack_frame(id, *address, *routine))
+            .collect();
+        json!({ "stackFrames": stack_frames, "totalFrames": locations.len() })
+    }
+
+    // Frames are named after the routine they are in, the outermost one after the closest label before it
+    fn stack_frame(&self, id: usize, address: u16, routine: Option<u16>) -> Value {
+        let name = match routine {
+            Some(entry) => self.address_name(entry),
+            None => match self.runner.symbols.names_in_range(0, address).last() {
+                Some((_, name)) => name.clone(),
+                None => format!("${:04X}", address),
+            },
+        };
+        let mut frame = json!({ "id": id, "name": name, "line": 0, "column": 0, "instructionPointerReference": format!("0x{:04X}", address) });
+        if let Some((source, line)) = self.source(address) {
+            frame["source"] = source;
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    fn source(&self, address: u16) -> Option<(Value, usize)> {
+        let info = self.runner.debug_info.as_ref()?;
+        let location = info.location_at(address)?;
+        let file = info.files.get(&location.file)?;
+        let name = Path::new(&file.name).file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let mut source = json!({ "name": name });
+        if let Some(path) = &file.path {
+            source["path"] = json!(path.to_string_lossy());
+        }
+        Some((source, location.line))
+    }
+
+    fn variables(&self, args: &Value) -> Value {
+        let cpu = &self.runner.cpu;
+        let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => vec![
+                json!({ "name": "A", "value": format!("${:02X}", cpu.a), "variablesReference": 0 }),
+                json!({ "name": "X", "value": format!("${:02X}", cpu.x), "variablesReference": 0 }),
+                json!({ "name": "Y", "value": format!("${:02X}", cpu.y), "variablesReference": 0 }),
+                json!({ "name": "SP", "value": format!("${:02X}", cpu.sp), "variablesReference": 0 }),
+                json!({ "name": "PC", "value": format!("${:04X}", cpu.pc), "variablesReference": 0, "memoryReference": format!("0x{:04X}", cpu.pc) }),
+                json!({ "name": "P", "value": format!("${:02X}", cpu.sr), "variablesReference": FLAGS_REFERENCE }),
+            ],
+            Some(FLAGS_REFERENCE) => FLAG_NAMES.iter().enumerate().filter(|(_, name)| **name != "-").map(|(bit, name)| {
+                json!({ "name": name, "value": if cpu.sr & (0x80 >> bit) != 0 {"1"} else {"0"}, "variablesReference": 0 })
+            }).collect(),
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or("");
+        let text = args["value"].as_str().unwrap_or("");
+        let value = parse_value(text).ok_or(format!("Invalid value {}", text))?;
+        let cpu = &mut self.runner.cpu;
+        if args["variablesReference"].as_u64() == Some(FLAGS_REFERENCE) {
+            let bit = FLAG_NAMES.iter().position(|flag| *flag == name).ok_or(format!("Unknown flag {}", name))?;
+            cpu.set_flag(value != 0, 0x80 >> bit);
+            return Ok(json!({ "value": if value != 0 {"1"} else {"0"} }));
+        }
+        if name == "PC" {
+            cpu.pc = value;
+            return Ok(json!({ "value": format!("${:04X}", value) }));
+        }
+        let register = match name {
+            "A" => &mut cpu.a,
+            "X" => &mut cpu.x,
+            "Y" => &mut cpu.y,
+            "SP" => &mut cpu.sp,
+            "P" => &mut cpu.sr,
+            _ => return Err(format!("Unknown register {}", name)),
+        };
+        *register = value as u8;
+        Ok(json!({ "value": format!("${:02X}", value as u8) }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let address = self.memory_arg(args)?;
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        let readable = count.min(self.runner.cpu.memory.len() - address as usize);
+        let bytes = &self.runner.cpu.memory[address as usize..address as usize + readable];
+        Ok(json!({ "address": format!("0x{:04X}", address), "data": encode_base64(bytes), "unreadableBytes": count - readable }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let address = self.memory_arg(args)?;
+        let data = decode_base64(args["data"].as_str().unwrap_or("")).ok_or("Invalid base64 data")?;
+        self.runner.poke(address, &data);
+        Ok(json!({ "bytesWritten": data.len() }))
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let mut address = self.runner.instruction_start(self.memory_arg(args)?, args["instructionOffset"].as_i64().unwrap_or(0));
+        let count = args["instructionCount"].as_u64().unwrap_or(0);
+        let mut instructions = Vec::new();
+        for _i in 0..count {
+            let instruction = self.runner.cpu.get_instruction_at(address);
+            let length = self.runner.instruction_length(address);
+            let bytes: Vec<String> = (0..length).map(|offset| format!("{:02X}", self.runner.cpu.memory[address.wrapping_add(offset) as usize])).collect();
+            let mut entry = json!({
+                "address": format!("0x{:04X}", address),
+                "instructionBytes": bytes.join(" "),
+                "instruction": format_instruction(&instruction, address, &self.runner.symbols),
+            });
+            if let Some(name) = self.runner.symbols.name_at(address) {
+                entry["symbol"] = json!(name);
+            }
+            if let Some((source, line)) = self.source(address) {
+                entry["location"] = source;
+                entry["line"] = json!(line);
+            }
+            instructions.push(entry);
+            address = address.wrapping_add(length);
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    // Registers by name, anything else is read as an address and shows the byte stored there
+    fn evaluate(&self, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().unwrap_or("").trim();
+        let cpu = &self.runner.cpu;
+        let register = match expression.to_uppercase().as_str() {
+            "A" => Some(format!("${:02X}", cpu.a)),
+            "X" => Some(format!("${:02X}", cpu.x)),
+            "Y" => Some(format!("${:02X}", cpu.y)),
+            "SP" => Some(format!("${:02X}", cpu.sp)),
+            "P" => Some(format!("${:02X}", cpu.sr)),
+            "PC" => Some(format!("${:04X}", cpu.pc)),
+            _ => None,
+        };
+        if let Some(result) = register {
+            return Ok(json!({ "result": result, "variablesReference": 0 }));
+        }
+        let address = self.runner.parse_address(expression).ok_or(format!("Unknown expression {}", expression))?;
+        Ok(json!({ "result": format!("${:02X}", cpu.memory[address as usize]), "variablesReference": 0, "memoryReference": format!("0x{:04X}", address) }))
+    }
+
+    fn address_arg(&self, value: &Value) -> Option<u16> {
+        match value {
+            Value::Number(number) => number.as_u64().and_then(|number| u16::try_from(number).ok()),
+            Value::String(text) => self.runner.parse_address(text),
+            _ => None,
+        }
+    }
+
+    // memoryReference plus the optional byte offset
+    fn memory_arg(&self, args: &Value) -> Result<u16, String> {
+        let address = self.address_arg(&args["memoryReference"]).ok_or("Invalid memory reference")?;
+        Ok(address.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16))
+    }
+
+    fn address_name(&self, address: u16) -> String {
+        match self.runner.symbols.name_at(address) {
+            Some(name) => name.to_string(),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) -> io::Result<()> {
+        self.running = false;
+        self.runner.step_target = None;
+        // Code the program patched would still show its old disassembly
+        if let Some((low, high)) = self.runner.smc_detector.patched.take().filter(|_| self.memory_events) {
+            self.event("memory", json!({ "memoryReference": format!("0x{:04X}", low), "offset": 0, "count": (high - low) as u32 + 1 }))?;
+        }
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        self.event("stopped", body)
+    }
+
+    // Messages for the user go to the debug console, stdout may be carrying the protocol
+    fn output(&mut self, text: &str) {
+        let _ = self.event("output", json!({ "category": "console", "output": format!("{}\n", text) }));
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({ "type": "response", "request_seq": request["seq"], "command": request["command"], "success": result.is_ok() });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+}
+
+fn unverified(lines: &[usize], message: &str) -> Value {
+    let breakpoints: Vec<Value> = lines.iter().map(|line| json!({ "verified": false, "line": line, "message": message })).collect();
+    json!({ "breakpoints": breakpoints })
+}
+
+// Register values are hex with a $ or 0x prefix, or decimal
+fn parse_value(text: &str) -> Option<u16> {
+    let text = text.trim();
+    match text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn read_messages<R: BufRead>(mut reader: R, sender: Sender<Value>) {
+    while let Some(message) = read_message(&mut reader) {
+        if sender.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+// Reads one Content-Length framed message, returning None at the end of the stream
+fn read_message<R: BufRead>(reader: &mut R) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse().ok();
+            }
+        }
+        let length = match length {
+            Some(length) => length,
+            None => continue,
+        };
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).ok()?;
+        if let Ok(message) = serde_json::from_slice(&body) {
+            return Some(message);
+        }
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| group | (*byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(BASE64_ALPHABET[(group >> (18 - 6 * index) & 0x3F) as usize] as char);
+            }
+            else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut group = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+        group = group << 6 | BASE64_ALPHABET.iter().position(|a| *a == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_base64, decode_base64};
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foo"), "Zm9v");
+        assert_eq!(encode_base64(&[0xFB, 0xFF, 0xBF]), "+/+/");
+    }
+
+    #[test]
+    fn base64_round_trips_every_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for length in [0, 1, 2, 3, 4, 255, 256] {
+            assert_eq!(decode_base64(&encode_base64(&bytes[..length])).as_deref(), Some(&bytes[..length]));
+        }
+    }
+
+    #[test]
+    fn base64_decoding_skips_whitespace_and_rejects_other_characters() {
+        assert_eq!(decode_base64("Zm9v\r\nYmFy").as_deref(), Some(&b"foobar"[..]));
+        assert_eq!(decode_base64("Zm8"), Some(b"fo".to_vec()));
+        assert_eq!(decode_base64("Zm9v!"), None);
+    }
+}
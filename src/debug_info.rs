@@ -0,0 +1,233 @@
+// Source line information from ld65 debug files (ld65 --dbgfile).
+// Lines refer to spans, which are byte ranges inside a segment, so every address of a span maps back to the line.
+
+use std::{fs::{read_to_string, canonicalize}, path::{Path, PathBuf}, collections::HashMap};
+
+use crate::symbols::{SymbolFileError, parse_dbg_fields};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    pub file: usize,
+    pub line: usize,
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub path: Option<PathBuf>,          // Absolute path of the source, None if it could not be found on disk
+    pub lines: Option<Vec<String>>,
+}
+
+struct Span {
+    seg: usize,
+    start: usize,
+    size: usize,
+}
+
+pub struct DebugInfo {
+    pub files: HashMap<usize, SourceFile>,
+    // Best location for every address, ranked by line type and then by the size of the span
+    locations: HashMap<u16, (SourceLocation, u8, usize)>,
+    // Start addresses of the code generated for each line
+    addresses: HashMap<SourceLocation, Vec<u16>>,
+}
+
+impl DebugInfo {
+    pub fn load_file(filename: &str) -> Result<Self, SymbolFileError> {
+        let contents = match read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(err) => return Err(SymbolFileError::new(&format!("Could not read {}: {}", filename, err))),
+        };
+        if !contents.trim_start().starts_with("version") {
+            return Err(SymbolFileError::new(&format!("{} is not an ld65 debug file", filename)));
+        }
+        let directory = Path::new(filename).parent().unwrap_or(Path::new(""));
+
+        let mut files = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut line_entries = Vec::new();
+        for line in contents.lines() {
+            let fields = parse_dbg_fields(line);
+            let id = match fields.get("id").and_then(|id| parse_number(id)) {
+                Some(id) => id,
+                None => continue,
+            };
+            if line.starts_with("file") {
+                let name = fields.get("name").cloned().unwrap_or_default();
+                let (path, lines) = match read_source(&name, directory) {
+                    Some((path, lines)) => (Some(path), Some(lines)),
+                    None => (None, None),
+                };
+                files.insert(id, SourceFile { name, path, lines });
+            }
+            else if line.starts_with("seg") {
+                segments.insert(id, fields.get("start").and_then(|start| parse_number(start)).unwrap_or(0));
+            }
+            else if line.starts_with("span") {
+                let number = |key: &str| fields.get(key).and_then(|value| parse_number(value)).unwrap_or(0);
+                spans.insert(id, Span { seg: number("seg"), start: number("start"), size: number("size") });
+            }
+            else if line.starts_with("line") {
+                line_entries.push(fields);
+            }
+        }
+
+        let mut info = DebugInfo { files, locations: HashMap::new(), addresses: HashMap::new() };
+        for fields in line_entries {
+            let number = |key: &str| fields.get(key).and_then(|value| parse_number(value));
+            let (file, line, span_list) = match (number("file"), number("line"), fields.get("span")) {
+                (Some(file), Some(line), Some(span_list)) => (file, line, span_list),
+                _ => continue,
+            };
+            let location = SourceLocation { file, line };
+            // C source lines (type 1) describe the code better than the assembly generated for them, macro expansions (type 2) worse
+            let rank = match number("type") {
+                Some(1) => 0,
+                Some(2) => 2,
+                _ => 1,
+            };
+            for span_id in span_list.split('+').filter_map(parse_number) {
+                let span = match spans.get(&span_id) {
+                    Some(span) if span.size > 0 => span,
+                    _ => continue,
+                };
+                let start = segments.get(&span.seg).copied().unwrap_or(0) + span.start;
+                info.addresses.entry(location).or_default().push(start as u16);
+                for address in start..start + span.size {
+                    let address = address as u16;
+                    let better = match info.locations.get(&address) {
+                        Some((_, best_rank, best_size)) => (rank, span.size) < (*best_rank, *best_size),
+                        None => true,
+                    };
+                    if better {
+                        info.locations.insert(address, (location, rank, span.size));
+                    }
+                }
+            }
+        }
+        for addresses in info.addresses.values_mut() {
+            addresses.sort_unstable();
+            addresses.dedup();
+        }
+        Ok(info)
+    }
+
+    pub fn location_at(&self, address: u16) -> Option<SourceLocation> {
+        self.locations.get(&address).map(|(location, _, _)| *location)
+    }
+
+    /// Start addresses of the code generated for a source line
+    pub fn addresses_of(&self, location: &SourceLocation) -> &[u16] {
+        self.addresses.get(location).map(|addresses| addresses.as_slice()).unwrap_or(&[])
+    }
+
+    /// Every source line that generated code, with the start addresses of that code
+    pub fn lines(&self) -> impl Iterator<Item = (&SourceLocation, &[u16])> {
+        self.addresses.iter().map(|(location, addresses)| (location, addresses.as_slice()))
+    }
+
+    /// Finds a file by its full name, by the end of its path, e.g. `main.s` for `src/main.s`, or by its location on disk
+    pub fn find_file(&self, name: &str) -> Option<usize> {
+        let path = canonicalize(name).ok();
+        self.files.iter()
+            .find(|(_, file)| file.name == name || Path::new(&file.name).ends_with(name) || (path.is_some() && file.path == path))
+            .map(|(id, _)| *id)
+    }
+
+    pub fn file_name(&self, file: usize) -> &str {
+        self.files.get(&file).map(|file| file.name.as_str()).unwrap_or("?")
+    }
+
+    pub fn source_line(&self, location: &SourceLocation) -> Option<&str> {
+        let lines = self.files.get(&location.file)?.lines.as_ref()?;
+        lines.get(location.line.checked_sub(1)?).map(|line| line.as_str())
+    }
+}
+
+fn parse_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// Paths in the debug file are relative to where ld65 ran, which is usually next to the debug file
+fn read_source(name: &str, directory: &Path) -> Option<(PathBuf, Vec<String>)> {
+    [PathBuf::from(name), directory.join(name)].into_iter().find_map(|path| {
+        let text = read_to_string(&path).ok()?;
+        let path = canonicalize(&path).unwrap_or(path);
+        Some((path, text.lines().map(|line| line.to_string()).collect()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, remove_file, write};
+
+    use super::{DebugInfo, SourceLocation};
+
+    const SOURCE: &str = "; test program\nstart:  ldx #$00\n        inx\n        nop\n        nop\n        nop\n        nop\n";
+
+    // prog.s generated the code, macros.inc only exists in the debug file. Lines 4 and 5 cover $0410 as assembly and as
+    // a C line, lines 6 and 7 cover $0420 with spans of different sizes.
+    const DEBUG_FILE: &str = "version\tmajor=2,minor=0
+file\tid=0,name=\"prog.s\",size=0,mtime=0x0,mod=0
+file\tid=1,name=\"include/macros.inc\",size=0,mtime=0x0,mod=0
+seg\tid=0,name=\"CODE\",start=0x000400,size=0x0030,addrsize=absolute,type=ro
+span\tid=0,seg=0,start=0,size=3
+span\tid=1,seg=0,start=3,size=1
+span\tid=2,seg=0,start=0,size=4
+span\tid=3,seg=0,start=16,size=2
+span\tid=4,seg=0,start=32,size=4
+span\tid=5,seg=0,start=33,size=1
+line\tid=0,file=0,line=2,span=0
+line\tid=1,file=0,line=3,span=1
+line\tid=2,file=1,line=7,span=2,type=2
+line\tid=3,file=0,line=4,span=3
+line\tid=4,file=0,line=5,span=3,type=1
+line\tid=5,file=0,line=6,span=4
+line\tid=6,file=0,line=7,span=5
+";
+
+    fn location(file: usize, line: usize) -> Option<SourceLocation> {
+        Some(SourceLocation { file, line })
+    }
+
+    #[test]
+    fn lines_map_to_addresses_and_back() {
+        let directory = std::env::temp_dir().join(format!("debug_info_test_{}", std::process::id()));
+        create_dir_all(&directory).expect("temporary directory");
+        write(directory.join("prog.s"), SOURCE).expect("temporary source");
+        write(directory.join("prog.dbg"), DEBUG_FILE).expect("temporary debug file");
+        let info = DebugInfo::load_file(directory.join("prog.dbg").to_str().unwrap());
+        remove_dir_all(&directory).ok();
+        let info = info.unwrap_or_else(|err| panic!("{}", err));
+
+        // Assembly lines rank above the macro expansions covering the same bytes
+        assert!(info.location_at(0x0401) == location(0, 2));
+        assert!(info.location_at(0x0403) == location(0, 3));
+        assert!(info.location_at(0x0404).is_none());
+        // C lines rank above assembly, and smaller spans above larger ones
+        assert!(info.location_at(0x0410) == location(0, 5));
+        assert!(info.location_at(0x0420) == location(0, 6));
+        assert!(info.location_at(0x0421) == location(0, 7));
+
+        assert_eq!(info.addresses_of(&SourceLocation { file: 0, line: 2 }), [0x0400]);
+        assert_eq!(info.addresses_of(&SourceLocation { file: 1, line: 7 }), [0x0400]);
+        assert!(info.addresses_of(&SourceLocation { file: 0, line: 1 }).is_empty());
+
+        assert_eq!((info.find_file("prog.s"), info.find_file("macros.inc"), info.find_file("other.s")), (Some(0), Some(1), None));
+        assert_eq!(info.file_name(1), "include/macros.inc");
+        assert_eq!(info.source_line(&SourceLocation { file: 0, line: 3 }), Some("        inx"));
+        assert_eq!(info.source_line(&SourceLocation { file: 1, line: 7 }), None);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let filename = std::env::temp_dir().join(format!("debug_info_test_{}.sym", std::process::id()));
+        write(&filename, "al C:0400 .start\n").expect("temporary label file");
+        let result = DebugInfo::load_file(filename.to_str().unwrap());
+        remove_file(&filename).ok();
+        assert!(result.is_err_and(|err| err.to_string().ends_with("is not an ld65 debug file")));
+    }
+}
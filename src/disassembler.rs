@@ -0,0 +1,38 @@
+use crate::{cpu_helpers::{Instruction, AddressMode}, symbols::SymbolTable};
+
+/// Formats an instruction in the usual assembler syntax, e.g. `LDA ($20),Y`.
+/// Addresses with a label are shown by name.
+pub fn format_instruction(instruction: &Instruction, pc: u16, symbols: &SymbolTable) -> String {
+    let mnemonic = instruction.operation.as_ref().to_uppercase();
+    let value = instruction.value;
+    let operand = match instruction.address_mode {
+        AddressMode::Acc => "A".to_string(),
+        AddressMode::Imp | AddressMode::Inv => return mnemonic,
+        AddressMode::Imm => format!("#${:02X}", value),
+        AddressMode::Zpg => address_name(value, 2, symbols),
+        AddressMode::Zpx => format!("{},X", address_name(value, 2, symbols)),
+        AddressMode::Zpy => format!("{},Y", address_name(value, 2, symbols)),
+        AddressMode::Abs => address_name(value, 4, symbols),
+        AddressMode::Abx => format!("{},X", address_name(value, 4, symbols)),
+        AddressMode::Aby => format!("{},Y", address_name(value, 4, symbols)),
+        AddressMode::Ind => format!("({})", address_name(value, 4, symbols)),
+        AddressMode::Inx => format!("({},X)", address_name(value, 2, symbols)),
+        AddressMode::Iny => format!("({}),Y", address_name(value, 2, symbols)),
+        // The offset is relative to the instruction following the branch
+        AddressMode::Rel => address_name(pc.wrapping_add(2).wrapping_add(value as u8 as i8 as u16), 4, symbols),
+    };
+    format!("{} {}", mnemonic, operand)
+}
+
+fn address_name(address: u16, digits: usize, symbols: &SymbolTable) -> String {
+    match symbols.name_at(address) {
+        Some(name) => name.to_string(),
+        None => format!("${:0width$X}", address, width = digits),
+    }
+}
+
+/// Formats bytes as an assembler data directive, e.g. `.byte $01, $FF`
+pub fn format_data(bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+    format!(".byte {}", values.join(", "))
+}
@@ -0,0 +1,294 @@
+// Expressions for debugger command arguments, e.g. `mem PC+3 10` or `break [$FFFC]`.
+//   numbers:    $FF or 0xFF hex, %1010 binary, #10 decimal, and digits without a prefix in the default radix of the argument
+//   names:      symbols, then the registers A X Y SP P (or SR) and PC, case insensitive. `*` in place of a value is the PC too.
+//   operators:  [addr] reads a byte and w[addr] or *[addr] a little endian word, so *[$20] follows the pointer at $20.
+//               Unary -, *, + -, << >>, & and | bind like in C.
+// A symbol shadows a register or hex number of the same name, and a register shadows hex, so $A is the number 10.
+
+use std::{fmt, iter::Peekable, str::Chars};
+
+use crate::{cpu::Cpu, symbols::SymbolTable};
+
+#[derive(Debug)]
+pub struct ExpressionError {
+    details: String
+}
+
+impl ExpressionError {
+    pub fn new(msg: &str) -> ExpressionError {
+        ExpressionError{details: msg.to_string()}
+    }
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}",self.details)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Operator(&'static str),
+    Open(char),
+    Close(char),
+}
+
+/// Evaluates `text`, reading registers and memory from `cpu`. Bare numbers are read in `radix`.
+pub fn evaluate(text: &str, cpu: &Cpu, symbols: &SymbolTable, radix: u32) -> Result<i64, ExpressionError> {
+    let mut parser = Parser { tokens: tokenize(text)?.into_iter().peekable(), cpu, symbols, radix };
+    let value = parser.or()?;
+    match parser.tokens.next() {
+        None => Ok(value),
+        Some(_) => Err(ExpressionError::new(&format!("unexpected text in {}", text))),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        }
+        else if c.is_ascii_alphanumeric() || "_.@$%#".contains(c) {
+            tokens.push(Token::Word(read_word(&mut chars)));
+        }
+        else {
+            chars.next();
+            let token = match c {
+                '(' | '[' => Token::Open(c),
+                ')' | ']' => Token::Close(c),
+                '+' => Token::Operator("+"),
+                '-' => Token::Operator("-"),
+                '*' => Token::Operator("*"),
+                '&' => Token::Operator("&"),
+                '|' => Token::Operator("|"),
+                '<' if chars.next_if_eq(&'<').is_some() => Token::Operator("<<"),
+                '>' if chars.next_if_eq(&'>').is_some() => Token::Operator(">>"),
+                _ => return Err(ExpressionError::new(&format!("unexpected {} in {}", c, text))),
+            };
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+// A name or number, where the first character may be a number prefix
+fn read_word(chars: &mut Peekable<Chars>) -> String {
+    let mut word: String = chars.next().into_iter().collect();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || "_.@".contains(*c)) {
+        word.push(c);
+    }
+    word
+}
+
+struct Parser<'a> {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+    cpu: &'a Cpu,
+    symbols: &'a SymbolTable,
+    radix: u32,
+}
+
+impl Parser<'_> {
+    fn or(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.and()?;
+        while self.tokens.next_if_eq(&Token::Operator("|")).is_some() {
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.shift()?;
+        while self.tokens.next_if_eq(&Token::Operator("&")).is_some() {
+            value &= self.shift()?;
+        }
+        Ok(value)
+    }
+
+    fn shift(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.sum()?;
+        while let Some(Token::Operator(operator)) = self.tokens.next_if(|token| *token == Token::Operator("<<") || *token == Token::Operator(">>")) {
+            let amount = self.sum()?;
+            if !(0..64).contains(&amount) {
+                return Err(ExpressionError::new(&format!("cannot shift by {}", amount)));
+            }
+            value = if operator == "<<" { value << amount } else { value >> amount };
+        }
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.product()?;
+        while let Some(Token::Operator(operator)) = self.tokens.next_if(|token| *token == Token::Operator("+") || *token == Token::Operator("-")) {
+            let operand = self.product()?;
+            value = if operator == "+" { value.wrapping_add(operand) } else { value.wrapping_sub(operand) };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.unary()?;
+        while self.tokens.next_if_eq(&Token::Operator("*")).is_some() {
+            value = value.wrapping_mul(self.unary()?);
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, ExpressionError> {
+        if self.tokens.next_if_eq(&Token::Operator("-")).is_some() {
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<i64, ExpressionError> {
+        match self.tokens.next() {
+            Some(Token::Operator("*")) if self.tokens.next_if_eq(&Token::Open('[')).is_some() => self.dereferenced_word(),
+            Some(Token::Operator("*")) => Ok(self.cpu.pc as i64),
+            Some(Token::Open('(')) => {
+                let value = self.or()?;
+                self.close(')')?;
+                Ok(value)
+            },
+            Some(Token::Open('[')) => {
+                let address = self.dereferenced_address()?;
+                Ok(self.cpu.memory[address as usize] as i64)
+            },
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("w") && self.tokens.next_if_eq(&Token::Open('[')).is_some() => self.dereferenced_word(),
+            Some(Token::Word(word)) => self.word(&word),
+            Some(Token::Close(c)) => Err(ExpressionError::new(&format!("unexpected {}", c))),
+            Some(Token::Operator(operator)) => Err(ExpressionError::new(&format!("unexpected {}", operator))),
+            Some(Token::Open(c)) => Err(ExpressionError::new(&format!("unexpected {}", c))),
+            None => Err(ExpressionError::new("missing value")),
+        }
+    }
+
+    // The expression inside [] or w[], after the opening bracket
+    fn dereferenced_address(&mut self) -> Result<u16, ExpressionError> {
+        let address = self.or()?;
+        self.close(']')?;
+        u16::try_from(address).map_err(|_| ExpressionError::new(&format!("address {} is out of range", address)))
+    }
+
+    fn dereferenced_word(&mut self) -> Result<i64, ExpressionError> {
+        let address = self.dereferenced_address()?;
+        Ok(self.cpu.read_memory_u16(address) as i64)
+    }
+
+    fn close(&mut self, bracket: char) -> Result<(), ExpressionError> {
+        match self.tokens.next() {
+            Some(Token::Close(c)) if c == bracket => Ok(()),
+            _ => Err(ExpressionError::new(&format!("missing {}", bracket))),
+        }
+    }
+
+    fn word(&self, word: &str) -> Result<i64, ExpressionError> {
+        if let Some(address) = self.symbols.address_of(word) {
+            return Ok(address as i64);
+        }
+        let cpu = self.cpu;
+        let register = match word.to_uppercase().as_str() {
+            "A" => Some(cpu.a as i64),
+            "X" => Some(cpu.x as i64),
+            "Y" => Some(cpu.y as i64),
+            "SP" => Some(cpu.sp as i64),
+            "P" | "SR" => Some(cpu.sr as i64),
+            "PC" => Some(cpu.pc as i64),
+            _ => None,
+        };
+        if let Some(value) = register {
+            return Ok(value);
+        }
+        let (digits, radix) = if let Some(digits) = word.strip_prefix('$').or_else(|| word.strip_prefix("0x")) {
+            (digits, 16)
+        }
+        else if let Some(digits) = word.strip_prefix('%') {
+            (digits, 2)
+        }
+        else if let Some(digits) = word.strip_prefix('#') {
+            (digits, 10)
+        }
+        else {
+            (word, self.radix)
+        };
+        i64::from_str_radix(digits, radix).map_err(|_| ExpressionError::new(&format!("unknown symbol or invalid number {}", word)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::evaluate;
+    use crate::{cpu::Cpu, symbols::SymbolTable};
+
+    fn value(text: &str, cpu: &Cpu, symbols: &SymbolTable) -> i64 {
+        evaluate(text, cpu, symbols, 16).unwrap_or_else(|err| panic!("{}: {}", text, err))
+    }
+
+    #[test]
+    fn operators_bind_like_in_c() {
+        let (cpu, symbols) = (Cpu::new(), SymbolTable::new());
+        assert_eq!(value("1+2*3", &cpu, &symbols), 7);
+        assert_eq!(value("(1+2)*3", &cpu, &symbols), 9);
+        assert_eq!(value("1<<2+1", &cpu, &symbols), 8);
+        assert_eq!(value("1|2&3", &cpu, &symbols), 3);
+        assert_eq!(value("$F0|$0F&$03", &cpu, &symbols), 0xF3);
+        assert_eq!(value("-2*3", &cpu, &symbols), -6);
+        assert_eq!(value("10-4-2", &cpu, &symbols), 0x0A);
+    }
+
+    #[test]
+    fn number_prefixes_override_the_radix() {
+        let (cpu, symbols) = (Cpu::new(), SymbolTable::new());
+        assert_eq!(value("$FF", &cpu, &symbols), 255);
+        assert_eq!(value("0xFF", &cpu, &symbols), 255);
+        assert_eq!(value("%1010", &cpu, &symbols), 10);
+        assert_eq!(value("#10", &cpu, &symbols), 10);
+        assert_eq!(value("10", &cpu, &symbols), 16);
+        assert_eq!(evaluate("10", &cpu, &symbols, 10).unwrap(), 10);
+    }
+
+    #[test]
+    fn symbols_shadow_registers_which_shadow_hex() {
+        let mut cpu = Cpu::new();
+        let mut symbols = SymbolTable::new();
+        cpu.a = 5;
+        cpu.pc = 0x0400;
+        assert_eq!(value("A", &cpu, &symbols), 5);
+        assert_eq!(value("$A", &cpu, &symbols), 10);
+        assert_eq!(value("pc+3", &cpu, &symbols), 0x0403);
+        assert_eq!(value("*", &cpu, &symbols), 0x0400);
+        assert_eq!(value("beef", &cpu, &symbols), 0xBEEF);
+        symbols.add("A", 0x1234);
+        symbols.add("beef", 0x2000);
+        assert_eq!(value("A", &cpu, &symbols), 0x1234);
+        assert_eq!(value("beef", &cpu, &symbols), 0x2000);
+        assert_eq!(value("$beef", &cpu, &symbols), 0xBEEF);
+    }
+
+    #[test]
+    fn brackets_read_memory() {
+        let mut cpu = Cpu::new();
+        let symbols = SymbolTable::new();
+        cpu.memory[0x20] = 0x34;
+        cpu.memory[0x21] = 0x12;
+        cpu.memory[0x1234] = 0x56;
+        cpu.memory[0xFFFF] = 0x78;
+        cpu.memory[0x0000] = 0x9A;
+        assert_eq!(value("[$20]", &cpu, &symbols), 0x34);
+        assert_eq!(value("[$20]+1", &cpu, &symbols), 0x35);
+        assert_eq!(value("w[$20]", &cpu, &symbols), 0x1234);
+        assert_eq!(value("*[$20]", &cpu, &symbols), 0x1234);
+        assert_eq!(value("[w[$20]]", &cpu, &symbols), 0x56);
+        assert_eq!(value("W[$FFFF]", &cpu, &symbols), 0x9A78);
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        let (cpu, symbols) = (Cpu::new(), SymbolTable::new());
+        for text in ["", "(1", "[$20", "1 2", "1+", "$10000]", "[$10000]", "1<<64", "nothing", "1 ^ 2"] {
+            assert!(evaluate(text, &cpu, &symbols, 16).is_err(), "{} should not evaluate", text);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// GDB remote serial protocol stub, so gdb or any other RSP client can drive the emulator over TCP.
+// Registers are numbered 0=A, 1=X, 2=Y, 3=P (status), 4=SP as single bytes and 5=PC as a 16 bit little endian value.
+// Only one client is served, and the emulator exits once it detaches.
+
+use std::{io::{self, Read, Write}, net::{TcpListener, TcpStream}, collections::HashMap};
+
+use crate::{cpu_runner::CpuRunner, breakpoint::BreakpointKind, smc_detector::SmcMode, stack_checker::StackCheckMode};
+
+pub const DEFAULT_PORT: u16 = 6502;
+
+const POLL_INTERVAL: usize = 1024;     // Instructions executed between checks for an interrupt from the client
+const REGISTER_BYTES: usize = 7;
+
+pub struct GdbStub<'a> {
+    runner: &'a mut CpuRunner,
+    stream: TcpStream,
+    breakpoints: HashMap<(u8, u16), usize>,    // (Z packet type, address) -> id in the runner's breakpoint list
+    stop_reply: String,
+}
+
+/// Waits for a client on `port` and serves it until it detaches or disconnects
+pub fn serve(runner: &mut CpuRunner, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for a GDB connection on port {}", port);
+    let (stream, address) = listener.accept()?;
+    println!("GDB connected from {}", address);
+    stream.set_nodelay(true)?;
+    let mut stub = GdbStub { runner, stream, breakpoints: HashMap::new(), stop_reply: "S05".to_string() };
+    stub.run()
+}
+
+impl GdbStub<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match packet.as_bytes().first() {
+                Some(b'k') => break,
+                Some(b'D') => {
+                    self.send("OK")?;
+                    break;
+                },
+                _ => {},
+            }
+            let reply = self.handle_packet(&packet)?;
+            self.send(&reply)?;
+        }
+        println!("GDB disconnected");
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: &str) -> io::Result<String> {
+        // Bytes that were not valid UTF-8 became multi byte replacement characters, so split after the first character
+        let first_length = packet.chars().next().map(char::len_utf8).unwrap_or(0);
+        let (command, args) = packet.split_at(first_length);
+        let reply = match command {
+            "?" => self.stop_reply.clone(),
+            "g" => encode_hex(&self.registers()),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() >= REGISTER_BYTES => {
+                    self.set_registers(&bytes);
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(5) => encode_hex(&self.registers()[5..7]),
+                Ok(reg) if reg < 5 => encode_hex(&self.registers()[reg..reg + 1]),
+                _ => "E01".to_string(),
+            },
+            "P" => self.write_register(args).unwrap_or_else(|| "E01".to_string()),
+            "m" => self.read_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "M" => self.write_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "s" | "c" => {
+                // An optional argument is the address to resume at
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    self.runner.cpu.pc = address;
+                }
+                self.stop_reply = self.resume(command == "s")?;
+                self.stop_reply.clone()
+            },
+            "Z" | "z" => self.set_breakpoint(args, command == "Z").unwrap_or_else(|| "E01".to_string()),
+            "H" | "T" => "OK".to_string(),
+            "q" => self.query(args),
+            // Anything else, including vCont and binary X packets, is reported as unsupported with an empty reply
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        let name = query.split([':', ',']).next().unwrap_or("");
+        match name {
+            "Supported" => "PacketSize=1000;swbreak+;hwbreak+".to_string(),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            "Symbol" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // Runs until a breakpoint, a watchpoint, a finding in break mode or an interrupt from the client, returning the stop reply
+    fn resume(&mut self, single_step: bool) -> io::Result<String> {
+        let mut count = 0;
+        loop {
+            self.runner.step();
+            // Findings go to the client's console, in break mode they stop execution as well
+            if let Some(hit) = self.runner.smc_hit.take() {
+                let description = self.runner.describe_smc_hit(&hit);
+                self.console(&description)?;
+                if self.runner.smc_detector.mode == SmcMode::Break {
+                    return Ok("S05".to_string());
+                }
+            }
+            if let Some(issue) = self.runner.stack_issue.take() {
+                let description = self.runner.describe_stack_issue(&issue);
+                self.console(&description)?;
+                if self.runner.stack_checker.mode == StackCheckMode::Break {
+                    return Ok("S05".to_string());
+                }
+            }
+            if let Some(hit) = self.runner.watch_hit.take() {
+                let kind = self.runner.breakpoints.iter().find(|bp| bp.id == hit.id).map(|bp| bp.kind);
+                let reason = match kind {
+                    Some(BreakpointKind::Read) => "rwatch",
+                    Some(BreakpointKind::Access) => "awatch",
+                    _ => "watch",
+                };
+                return Ok(format!("T05{}:{:04x};", reason, hit.address));
+            }
+            if single_step {
+                return Ok("S05".to_string());
+            }
+            if self.runner.breakpoints.hit(self.runner.cpu.pc).is_some() {
+                return Ok("T05swbreak:;".to_string());
+            }
+            count += 1;
+            if count % POLL_INTERVAL == 0 && self.interrupt_requested()? {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    // Checks for the 0x03 byte the client sends to interrupt a running target
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GDB disconnected")),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn registers(&self) -> [u8; REGISTER_BYTES] {
+        let cpu = &self.runner.cpu;
+        [cpu.a, cpu.x, cpu.y, cpu.sr, cpu.sp, cpu.pc as u8, (cpu.pc >> 8) as u8]
+    }
+
+    fn set_registers(&mut self, bytes: &[u8]) {
+        let cpu = &mut self.runner.cpu;
+        cpu.a = bytes[0];
+        cpu.x = bytes[1];
+        cpu.y = bytes[2];
+        cpu.sr = bytes[3];
+        cpu.sp = bytes[4];
+        cpu.pc = u16::from_le_bytes([bytes[5], bytes[6]]);
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (reg, value) = args.split_once('=')?;
+        let reg = usize::from_str_radix(reg, 16).ok()?;
+        let value = decode_hex(value)?;
+        let mut registers = self.registers();
+        match reg {
+            0..=4 => registers[reg] = *value.first()?,
+            5 => registers[5..7].copy_from_slice(value.get(0..2)?),
+            _ => return None,
+        }
+        self.set_registers(&registers);
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = parse_address_length(args)?;
+        let bytes: Vec<u8> = (0..length).map(|offset| self.runner.cpu.memory[address.wrapping_add(offset) as usize]).collect();
+        Some(encode_hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = parse_address_length(range)?;
+        let bytes = decode_hex(data)?;
+        if bytes.len() != length as usize {
+            return None;
+        }
+        self.runner.poke(address, &bytes);
+        Some("OK".to_string())
+    }
+
+    // Z0/Z1 are execution breakpoints, Z2-Z4 write, read and access watchpoints where the last field is the length
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind_number: u8 = fields.next()?.parse().ok()?;
+        let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let length = u16::from_str_radix(fields.next().unwrap_or("1"), 16).ok()?;
+        let kind = match kind_number {
+            0 | 1 => BreakpointKind::Execute,
+            2 => BreakpointKind::Write,
+            3 => BreakpointKind::Read,
+            4 => BreakpointKind::Access,
+            _ => return Some(String::new()),
+        };
+        let key = (kind_number, address);
+        if insert {
+            if !self.breakpoints.contains_key(&key) {
+                let id = match kind {
+                    BreakpointKind::Execute => self.runner.breakpoints.add(address, None, false),
+                    _ => self.runner.breakpoints.add_watchpoint(kind, address, length),
+                };
+                self.breakpoints.insert(key, id);
+            }
+        }
+        else if let Some(id) = self.breakpoints.remove(&key) {
+            self.runner.breakpoints.remove(id);
+        }
+        Some("OK".to_string())
+    }
+
+    // Reads a $data#checksum packet, acknowledging it. Returns None once the client disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                // Acks and interrupts while stopped are ignored
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if expected == Some(checksum_of(&data)) && !data.is_empty() {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Output packets are only allowed while the target runs, before its stop reply
+    fn console(&mut self, text: &str) -> io::Result<()> {
+        self.send(&format!("O{}", encode_hex(format!("{}\n", text).as_bytes())))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_address_length(args: &str) -> Option<(u16, u16)> {
+    let (address, length) = args.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u16::from_str_radix(length, 16).ok()?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::{Read, Write}, net::{TcpListener, TcpStream}};
+
+    use crate::cpu_runner::CpuRunner;
+    use super::{checksum_of, decode_hex, encode_hex, parse_address_length, GdbStub};
+
+    // Returns a stub and the client end of its connection
+    fn connect(runner: &mut CpuRunner) -> (GdbStub<'_>, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).expect("listener");
+        let client = TcpStream::connect(listener.local_addr().unwrap()).expect("client");
+        let (stream, _) = listener.accept().expect("connection");
+        (GdbStub { runner, stream, breakpoints: HashMap::new(), stop_reply: "S05".to_string() }, client)
+    }
+
+    fn packet(stub: &mut GdbStub, packet: &str) -> String {
+        stub.handle_packet(packet).expect("packet")
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+        assert_eq!(checksum_of(b"qSupported:swbreak+"), 0x8B);
+        assert_eq!(parse_address_length("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_address_length("c000"), None);
+        assert_eq!(encode_hex(&[0x00, 0xAB, 0x7F]), "00ab7f");
+        assert_eq!(decode_hex("00AB7f"), Some(vec![0x00, 0xAB, 0x7F]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn packets_are_checked_and_acknowledged() {
+        let mut runner = CpuRunner::new();
+        let (mut stub, mut client) = connect(&mut runner);
+        client.write_all(b"+$g#00$g#67").unwrap();
+        assert_eq!(stub.read_packet().unwrap(), Some("g".to_string()));
+        stub.send("OK").unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).unwrap();
+        // The bad checksum is rejected before the good packet is acknowledged
+        assert_eq!(&reply, b"-+$OK#9a");
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut runner = CpuRunner::new();
+        let (mut stub, _client) = connect(&mut runner);
+        assert_eq!(packet(&mut stub, "G01020330fd0080"), "OK");
+        assert_eq!(packet(&mut stub, "g"), "01020330fd0080");
+        assert_eq!(packet(&mut stub, "p5"), "0080");
+        assert_eq!(packet(&mut stub, "P1=7f"), "OK");
+        assert_eq!(packet(&mut stub, "p1"), "7f");
+        assert_eq!(packet(&mut stub, "G0102"), "E01");
+
+        assert_eq!(packet(&mut stub, "M02fe,4:a1b2c3d4"), "OK");
+        assert_eq!(packet(&mut stub, "m02fe,4"), "a1b2c3d4");
+        assert_eq!(packet(&mut stub, "Mffff,2:1122"), "OK");
+        assert_eq!(stub.runner.cpu.memory[0], 0x22);
+        assert_eq!(packet(&mut stub, "M0200,2:11"), "E01");
+        assert_eq!(packet(&mut stub, "m0200"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut runner = CpuRunner::new();
+        // 0400 INX, 0401 STX $0200, 0404 JMP $0400
+        runner.cpu.memory[0x0400..0x0407].copy_from_slice(&[0xE8, 0x8E, 0x00, 0x02, 0x4C, 0x00, 0x04]);
+        runner.cpu.pc = 0x0400;
+        let (mut stub, _client) = connect(&mut runner);
+        assert_eq!(packet(&mut stub, "Z0,404,1"), "OK");
+        assert_eq!(packet(&mut stub, "Z0,404,1"), "OK");
+        assert_eq!(stub.runner.breakpoints.iter().count(), 1);
+        assert_eq!(packet(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(stub.runner.cpu.pc, 0x0404);
+        assert_eq!(packet(&mut stub, "z0,404,1"), "OK");
+        assert!(stub.runner.breakpoints.is_empty());
+
+        assert_eq!(packet(&mut stub, "Z2,200,1"), "OK");
+        assert_eq!(packet(&mut stub, "c"), "T05watch:0200;");
+        assert_eq!(packet(&mut stub, "?"), "T05watch:0200;");
+        assert_eq!(packet(&mut stub, "s"), "S05");
+        assert_eq!(packet(&mut stub, "Z5,200,1"), "");
+    }
+}
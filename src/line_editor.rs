@@ -0,0 +1,108 @@
+// Prompt with line editing, a history that is kept across sessions and tab completion of commands and symbols.
+
+use std::path::PathBuf;
+
+use rustyline::{Editor, Helper, Context, completion::Completer, hint::Hinter, highlight::Highlighter, validate::Validator, history::DefaultHistory, error::ReadlineError};
+
+const HISTORY_FILE: &str = ".nes_emulator_history";
+const PROMPT: &str = "> ";
+
+// Completion candidates, updated by the runner as symbols are loaded and macros defined
+pub struct CommandHelper {
+    pub commands: Vec<String>,
+    pub symbols: Vec<String>,
+}
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    // The first word is completed as a command, any later one as a symbol
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(' ').map(|index| index + 1).unwrap_or(0);
+        let word = &line[start..pos];
+        let names = if start == 0 { &self.commands } else { &self.symbols };
+        let mut candidates: Vec<String> = names.iter().filter(|name| name.starts_with(word)).cloned().collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+pub struct LineEditor {
+    editor: Editor<CommandHelper, DefaultHistory>,
+    history_file: PathBuf,
+}
+
+impl LineEditor {
+    /// Sets up the terminal and loads the history of earlier sessions from ~/.nes_emulator_history
+    pub fn new() -> Result<Self, ReadlineError> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(CommandHelper { commands: Vec::new(), symbols: Vec::new() }));
+        let history_file = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(HISTORY_FILE);
+        // There is no history file before the first session
+        let _ = editor.load_history(&history_file);
+        Ok(LineEditor { editor, history_file })
+    }
+
+    pub fn helper_mut(&mut self) -> &mut CommandHelper {
+        self.editor.helper_mut().expect("the helper is set in new")
+    }
+
+    /// Reads a line, where Ctrl+C discards what was typed and prompts again. Returns None at the end of input.
+    pub fn read_line(&mut self) -> Option<String> {
+        loop {
+            match self.editor.readline(PROMPT) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = self.editor.add_history_entry(line.as_str());
+                        if let Err(err) = self.editor.append_history(&self.history_file) {
+                            println!("Could not save the command history to {}: {}", self.history_file.display(), err);
+                        }
+                    }
+                    return Some(line);
+                },
+                Err(ReadlineError::Interrupted) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustyline::{Context, completion::Completer, history::DefaultHistory};
+
+    use super::CommandHelper;
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let helper = CommandHelper {
+            commands: ["break", "bt", "b", "back", "bt"].iter().map(|name| name.to_string()).collect(),
+            symbols: ["reset", "read_joy", "main"].iter().map(|name| name.to_string()).collect(),
+        };
+        let history = DefaultHistory::new();
+        helper.complete(line, line.len(), &Context::new(&history)).expect("completion")
+    }
+
+    #[test]
+    fn first_word_completes_commands() {
+        assert_eq!(complete("b"), (0, vec!["b".to_string(), "back".to_string(), "break".to_string(), "bt".to_string()]));
+        assert_eq!(complete("br"), (0, vec!["break".to_string()]));
+        assert_eq!(complete("x").1, Vec::<String>::new());
+    }
+
+    #[test]
+    fn later_words_complete_symbols() {
+        assert_eq!(complete("break re"), (6, vec!["read_joy".to_string(), "reset".to_string()]));
+        assert_eq!(complete("mem main 10 b").1, Vec::<String>::new());
+    }
+}
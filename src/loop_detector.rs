@@ -0,0 +1,158 @@
+// Detects when the program is stuck, i.e. it keeps cycling through the same registers without changing memory.
+// Test programs like the functional test end in such a trap, and which address it is at tells whether they passed.
+
+use std::collections::BTreeMap;
+
+use crate::cpu_helpers::CpuState;
+
+const DEFAULT_ITERATIONS: usize = 2;
+const DEFAULT_MAX_LENGTH: usize = 256;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TrapKind {
+    Success,
+    Failure,
+}
+
+impl TrapKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrapKind::Success => "success",
+            TrapKind::Failure => "failure",
+        }
+    }
+}
+
+pub struct LoopHit {
+    pub pc: u16,
+    pub length: usize,                  // Instructions per iteration
+    pub trap: Option<(u16, TrapKind)>,  // A trap address the loop goes through
+}
+
+pub struct LoopDetector {
+    pub iterations: usize,  // Repeats of the same state needed to report a loop, 0 disables detection
+    pub max_length: usize,  // Longest loop that is detected, in instructions
+    pub traps: BTreeMap<u16, TrapKind>,
+    checkpoint: Option<CpuState>,
+    repeats: usize,
+    pcs: Vec<u16>,          // Executed since the checkpoint or its last repeat
+}
+
+impl LoopDetector {
+    pub fn new() -> Self {
+        LoopDetector { iterations: DEFAULT_ITERATIONS, max_length: DEFAULT_MAX_LENGTH, traps: BTreeMap::new(), checkpoint: None, repeats: 0, pcs: Vec::new() }
+    }
+
+    /// Forgets the states seen so far
+    pub fn reset(&mut self) {
+        self.checkpoint = None;
+        self.repeats = 0;
+        self.pcs.clear();
+    }
+
+    /// Checks the state an instruction left, returning a hit once the loop it is in has repeated often enough.
+    /// Without memory changes the cpu is deterministic, so a state coming back means it will keep coming back.
+    pub fn update(&mut self, state: &CpuState, memory_changed: bool) -> Option<LoopHit> {
+        if self.iterations == 0 {
+            return None;
+        }
+        let checkpoint = match &self.checkpoint {
+            Some(checkpoint) if !memory_changed => checkpoint,
+            _ => {
+                self.restart(state);
+                return None;
+            },
+        };
+
+        if same_registers(checkpoint, state) {
+            self.repeats += 1;
+            let length = self.pcs.len() + 1;
+            if self.repeats >= self.iterations {
+                let traps = &self.traps;
+                let trap = self.pcs.iter().chain([state.pc].iter()).find_map(|pc| traps.get(pc).map(|kind| (*pc, *kind)));
+                self.reset();
+                return Some(LoopHit { pc: state.pc, length, trap });
+            }
+            self.pcs.clear();
+        }
+        else if self.pcs.len() < self.max_length {
+            self.pcs.push(state.pc);
+        }
+        else {
+            // Longer than any loop looked for, so look for one starting here instead
+            self.restart(state);
+        }
+        None
+    }
+
+    /// Looks for loops coming back to `state`, e.g. the current one after the debugger changed memory or registers
+    pub fn restart(&mut self, state: &CpuState) {
+        self.reset();
+        self.checkpoint = Some(*state);
+    }
+}
+
+// Cycle counts keep going up, so they are not part of the state that repeats
+fn same_registers(a: &CpuState, b: &CpuState) -> bool {
+    a.a == b.a && a.x == b.x && a.y == b.y && a.pc == b.pc && a.sp == b.sp && a.sr == b.sr
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu_helpers::CpuState;
+    use super::{LoopDetector, TrapKind};
+
+    fn state(pc: u16, x: u8) -> CpuState {
+        let mut state = CpuState::new();
+        state.pc = pc;
+        state.x = x;
+        state
+    }
+
+    // Feeds the states in order, returning after how many a loop was reported
+    fn run(detector: &mut LoopDetector, states: impl Iterator<Item = (CpuState, bool)>) -> Option<usize> {
+        for (count, (state, memory_changed)) in states.enumerate() {
+            if detector.update(&state, memory_changed).is_some() {
+                return Some(count + 1);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn jump_to_self_is_a_loop() {
+        let mut detector = LoopDetector::new();
+        assert!(detector.update(&state(0x0400, 0), false).is_none());
+        assert!(detector.update(&state(0x0400, 0), false).is_none());
+        let hit = detector.update(&state(0x0400, 0), false).expect("loop");
+        assert_eq!((hit.pc, hit.length), (0x0400, 1));
+        assert!(hit.trap.is_none());
+    }
+
+    #[test]
+    fn loops_through_a_trap_name_it() {
+        let mut detector = LoopDetector::new();
+        detector.traps.insert(0x0402, TrapKind::Success);
+        let states = [0x0400, 0x0402].into_iter().cycle().map(|pc| (state(pc, 0), false));
+        assert_eq!(run(&mut detector, states.take(10)), Some(5));
+
+        detector.iterations = 3;
+        let states = [0x0400, 0x0402].into_iter().cycle().map(|pc| (state(pc, 0), false));
+        let hits: Vec<_> = states.take(7).filter_map(|(state, changed)| detector.update(&state, changed)).collect();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].length, hits[0].trap.map(|(pc, kind)| (pc, kind == TrapKind::Success))), (2, Some((0x0402, true))));
+    }
+
+    #[test]
+    fn programs_making_progress_are_not_loops() {
+        let mut detector = LoopDetector::new();
+        // Same registers, but every iteration writes memory
+        assert_eq!(run(&mut detector, (0..1000).map(|_| (state(0x0400, 0), true))), None);
+        // A counter in X, longer than the longest loop looked for
+        let states = (0..1000).flat_map(|x| [(state(0x0400, x as u8), false), (state(0x0401, x as u8), false)]);
+        assert_eq!(run(&mut detector, states), None);
+
+        detector.iterations = 0;
+        assert_eq!(run(&mut detector, (0..10).map(|_| (state(0x0400, 0), false))), None);
+    }
+}
@@ -0,0 +1,137 @@
+// Formats of the mem command, and the character sets of its text column.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MemoryFormat {
+    Hex,
+    Ascii,
+    Petscii,
+    Atascii,
+    Words,      // Little endian
+    Signed,
+    Binary,
+    Pointers,   // One little endian word per line, with the label it points to
+}
+
+impl MemoryFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "hex" => Some(MemoryFormat::Hex),
+            "ascii" => Some(MemoryFormat::Ascii),
+            "petscii" => Some(MemoryFormat::Petscii),
+            "atascii" => Some(MemoryFormat::Atascii),
+            "words" => Some(MemoryFormat::Words),
+            "signed" => Some(MemoryFormat::Signed),
+            "binary" => Some(MemoryFormat::Binary),
+            "pointers" => Some(MemoryFormat::Pointers),
+            _ => None,
+        }
+    }
+
+    /// Bytes shown per cell
+    pub fn unit_size(&self) -> usize {
+        match self {
+            MemoryFormat::Words | MemoryFormat::Pointers => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn row_length(&self) -> usize {
+        match self {
+            MemoryFormat::Binary => 8,
+            _ => 16,
+        }
+    }
+
+    pub fn cell_width(&self) -> usize {
+        match self {
+            MemoryFormat::Words | MemoryFormat::Signed => 4,
+            MemoryFormat::Binary => 8,
+            _ => 2,
+        }
+    }
+
+    /// Formats the cell starting with `low`, where `high` is only used by word formats
+    pub fn format_cell(&self, low: u8, high: u8) -> String {
+        match self {
+            MemoryFormat::Words | MemoryFormat::Pointers => format!("{:04X}", u16::from_le_bytes([low, high])),
+            MemoryFormat::Signed => format!("{:>4}", low as i8),
+            MemoryFormat::Binary => format!("{:08b}", low),
+            _ => format!("{:02X}", low),
+        }
+    }
+
+    /// The character a byte shows as in the text column, for the formats that have one
+    pub fn text_char(&self, byte: u8) -> Option<char> {
+        match self {
+            MemoryFormat::Ascii => Some(ascii_char(byte)),
+            MemoryFormat::Petscii => Some(petscii_char(byte)),
+            MemoryFormat::Atascii => Some(atascii_char(byte)),
+            _ => None,
+        }
+    }
+}
+
+fn ascii_char(byte: u8) -> char {
+    match byte {
+        0x20..=0x7E => byte as char,
+        _ => '.',
+    }
+}
+
+// The uppercase and graphics set the C64 starts in, graphics characters show as dots
+fn petscii_char(byte: u8) -> char {
+    match byte {
+        0x5C => '£',
+        0x5E => '↑',
+        0x5F => '←',
+        0x20..=0x5D => byte as char,
+        0xA0 => ' ',
+        _ => '.',
+    }
+}
+
+// Bytes with bit 7 set are the inverse video versions of the same characters
+fn atascii_char(byte: u8) -> char {
+    match byte & 0x7F {
+        0x60 => '♦',
+        0x7B => '♠',
+        character @ (0x20..=0x7C) => character as char,
+        _ => '.',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryFormat;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        assert!(MemoryFormat::from_name("PETSCII") == Some(MemoryFormat::Petscii));
+        assert!(MemoryFormat::from_name("words") == Some(MemoryFormat::Words));
+        assert!(MemoryFormat::from_name("octal").is_none());
+    }
+
+    #[test]
+    fn cells_fill_their_width() {
+        assert_eq!(MemoryFormat::Hex.format_cell(0x0A, 0xFF), "0A");
+        assert_eq!(MemoryFormat::Words.format_cell(0x34, 0x12), "1234");
+        assert_eq!(MemoryFormat::Pointers.format_cell(0xFC, 0xFF), "FFFC");
+        assert_eq!(MemoryFormat::Signed.format_cell(0xFF, 0), "  -1");
+        assert_eq!(MemoryFormat::Signed.format_cell(0x80, 0), "-128");
+        assert_eq!(MemoryFormat::Binary.format_cell(0x05, 0), "00000101");
+        for format in [MemoryFormat::Hex, MemoryFormat::Words, MemoryFormat::Signed, MemoryFormat::Binary] {
+            assert_eq!(format.format_cell(0xA5, 0x5A).chars().count(), format.cell_width());
+        }
+    }
+
+    #[test]
+    fn text_columns_use_the_machine_character_set() {
+        assert_eq!(MemoryFormat::Ascii.text_char(b'A'), Some('A'));
+        assert_eq!(MemoryFormat::Ascii.text_char(0x7F), Some('.'));
+        assert_eq!(MemoryFormat::Petscii.text_char(0x5C), Some('£'));
+        assert_eq!(MemoryFormat::Petscii.text_char(0x61), Some('.'));
+        assert_eq!(MemoryFormat::Atascii.text_char(b'A' | 0x80), Some('A'));
+        assert_eq!(MemoryFormat::Atascii.text_char(0x7B), Some('♠'));
+        assert_eq!(MemoryFormat::Hex.text_char(b'A'), None);
+    }
+}
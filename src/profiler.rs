@@ -0,0 +1,123 @@
+// Counts executions and cycles per address, and attributes cycles to the routines on the shadow call stack.
+// Exclusive cycles are spent in a routine's own instructions, inclusive ones also in the routines it called.
+// JSR is charged to the caller and RTS to the routine returning, like a sampling profiler would see them.
+
+use std::{collections::HashMap, io::{self, Write}};
+
+use crate::call_stack::Frame;
+
+#[derive(Clone, Copy, Default)]
+pub struct RoutineProfile {
+    pub calls: u64,
+    pub inclusive_cycles: u64,
+    pub exclusive_cycles: u64,
+}
+
+pub struct Profiler {
+    pub recording: bool,
+    pub executions: Vec<u64>,   // Per address, counting instruction starts
+    pub cycles: Vec<u64>,       // Per address, of the instructions starting there
+    pub routines: HashMap<u16, RoutineProfile>,     // By entry point
+    pub top_level_cycles: u64,  // Spent outside of any routine
+    pub total_cycles: u64,
+    pub instructions: u64,
+    stacks: HashMap<Vec<u16>, u64>,     // Exclusive cycles of every call path, outermost routine first
+    path: Vec<u16>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler { recording: true, executions: vec![0; 0x10000], cycles: vec![0; 0x10000], routines: HashMap::new(), top_level_cycles: 0, total_cycles: 0, instructions: 0, stacks: HashMap::new(), path: Vec::new() }
+    }
+
+    /// Records an instruction at `pc` that took `cycles`, executed inside `frames`.
+    /// `called` is the entry point of the routine it called, if any.
+    pub fn record(&mut self, pc: u16, cycles: u64, frames: &[Frame], called: Option<u16>) {
+        self.executions[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+        self.total_cycles += cycles;
+        self.instructions += 1;
+
+        if let Some(target) = called {
+            self.routines.entry(target).or_default().calls += 1;
+        }
+        match frames.last() {
+            Some(frame) => self.routines.entry(frame.target).or_default().exclusive_cycles += cycles,
+            None => self.top_level_cycles += cycles,
+        }
+        // A recursive routine only counts once, or its inclusive cycles would exceed the total
+        for (depth, frame) in frames.iter().enumerate() {
+            if !frames[..depth].iter().any(|outer| outer.target == frame.target) {
+                self.routines.entry(frame.target).or_default().inclusive_cycles += cycles;
+            }
+        }
+
+        self.path.clear();
+        self.path.extend(frames.iter().map(|frame| frame.target));
+        match self.stacks.get_mut(self.path.as_slice()) {
+            Some(stack_cycles) => *stack_cycles += cycles,
+            None => {
+                self.stacks.insert(self.path.clone(), cycles);
+            },
+        }
+    }
+
+    /// Writes one line per call path in the collapsed stack format of flamegraph.pl and inferno,
+    /// e.g. `top_level;main;print 1234`, with the cycles spent in the innermost routine
+    pub fn write_collapsed(&self, writer: &mut impl Write, name: impl Fn(u16) -> String) -> io::Result<usize> {
+        let mut lines: Vec<String> = self.stacks.iter().map(|(path, cycles)| {
+            let mut line = "top_level".to_string();
+            for target in path {
+                line.push(';');
+                line.push_str(&name(*target).replace([';', ' '], "_"));
+            }
+            format!("{} {}", line, cycles)
+        }).collect();
+        lines.sort();
+        for line in lines.iter() {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(lines.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu_runner::CpuRunner;
+    use super::Profiler;
+
+    // 0400 JSR $0410, 0403 JSR $0410, 0406 NOP, 0410 JSR $0420, 0413 RTS, 0420 INX, 0421 RTS
+    fn profiled_run() -> Profiler {
+        let mut runner = CpuRunner::new();
+        for (address, bytes) in [(0x0400, &[0x20, 0x10, 0x04, 0x20, 0x10, 0x04, 0xEA][..]), (0x0410, &[0x20, 0x20, 0x04, 0x60]), (0x0420, &[0xE8, 0x60])] {
+            runner.cpu.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        runner.cpu.pc = 0x0400;
+        runner.profiler = Some(Profiler::new());
+        for _i in 0..11 {
+            runner.step();
+        }
+        assert_eq!(runner.cpu.pc, 0x0407);
+        runner.profiler.take().unwrap()
+    }
+
+    #[test]
+    fn cycles_are_attributed_to_routines() {
+        let profiler = profiled_run();
+        assert_eq!((profiler.instructions, profiler.total_cycles, profiler.top_level_cycles), (11, 54, 14));
+        assert_eq!((profiler.executions[0x0420], profiler.cycles[0x0420], profiler.executions[0x0406]), (2, 4, 1));
+        let routine = |target| profiler.routines.get(&target).map(|routine| (routine.calls, routine.exclusive_cycles, routine.inclusive_cycles));
+        assert_eq!(routine(0x0410), Some((2, 24, 40)));
+        assert_eq!(routine(0x0420), Some((2, 16, 16)));
+        assert_eq!(routine(0x0400), None);
+    }
+
+    #[test]
+    fn call_paths_are_written_collapsed() {
+        let profiler = profiled_run();
+        let mut output = Vec::new();
+        let name = |address| if address == 0x0410 { "main loop".to_string() } else { format!("{:04X}", address) };
+        assert_eq!(profiler.write_collapsed(&mut output, name).unwrap(), 3);
+        assert_eq!(String::from_utf8(output).unwrap(), "top_level 14\ntop_level;main_loop 24\ntop_level;main_loop;0420 16\n");
+    }
+}
@@ -0,0 +1,137 @@
+// Detects self-modifying code, i.e. the program writing to bytes it executed before as an opcode or operand.
+// Every site, a writing instruction and its target, is kept with a count, and only its first write is reported unless breaking.
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SmcMode {
+    Off,
+    Report,     // Report the first write of every site and keep running
+    Break,      // Stop execution at every write
+}
+
+#[derive(Clone, Copy)]
+pub struct SmcHit {
+    pub pc: u16,            // Address of the writing instruction
+    pub address: u16,
+    pub old_value: u8,
+    pub new_value: u8,
+    pub operand: bool,      // Executed as an operand rather than an opcode
+}
+
+pub struct SmcDetector {
+    pub mode: SmcMode,
+    pub sites: BTreeMap<(u16, u16), usize>,     // Writes by writing instruction and target
+    pub patched: Option<(u16, u16)>,    // Lowest and highest executed byte changed since a front end last took it
+    executed: Vec<u8>,      // 1 for opcodes, 2 for operands, tracked even while off so turning it on knows what ran
+}
+
+impl SmcDetector {
+    pub fn new() -> Self {
+        SmcDetector { mode: SmcMode::Off, sites: BTreeMap::new(), patched: None, executed: vec![0; 0x10000] }
+    }
+
+    pub fn set_mode(&mut self, mode: SmcMode) {
+        self.mode = mode;
+    }
+
+    /// Forgets the executed bytes and the sites found
+    pub fn reset(&mut self) {
+        self.executed.fill(0);
+        self.sites.clear();
+        self.patched = None;
+    }
+
+    /// Records an instruction of `size` bytes executed at `pc`, then unless off checks its writes, given as (address, old value).
+    /// Returns the hit to surface, if any.
+    pub fn update(&mut self, pc: u16, size: u16, writes: &[(u16, u8)], memory: &[u8]) -> Option<SmcHit> {
+        // Marked first, an instruction overwriting its own bytes patches code that already ran
+        self.executed[pc as usize] = 1;
+        for offset in 1..size {
+            self.executed[pc.wrapping_add(offset) as usize] = 2;
+        }
+        if self.mode == SmcMode::Off {
+            return None;
+        }
+        let mut result = None;
+        for (address, old_value) in writes {
+            let new_value = memory[*address as usize];
+            // Writing the same value back changes no code
+            if self.executed[*address as usize] == 0 || new_value == *old_value {
+                continue;
+            }
+            self.patched = Some(match self.patched {
+                Some((low, high)) => (low.min(*address), high.max(*address)),
+                None => (*address, *address),
+            });
+            let count = self.sites.entry((pc, *address)).or_insert(0);
+            *count += 1;
+            if result.is_none() && (*count == 1 || self.mode == SmcMode::Break) {
+                result = Some(SmcHit { pc, address: *address, old_value: *old_value, new_value, operand: self.executed[*address as usize] == 2 });
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SmcDetector, SmcMode};
+
+    // Executes a three byte instruction at $0400, then one at $0410 that writes `value` to `address`
+    fn write_after_executing(detector: &mut SmcDetector, memory: &mut [u8], address: u16, value: u8) -> Option<super::SmcHit> {
+        assert!(detector.update(0x0400, 3, &[], memory).is_none());
+        let old_value = memory[address as usize];
+        memory[address as usize] = value;
+        detector.update(0x0410, 3, &[(address, old_value)], memory)
+    }
+
+    #[test]
+    fn finds_writes_to_executed_opcodes_and_operands() {
+        let mut detector = SmcDetector::new();
+        let mut memory = vec![0u8; 0x10000];
+        detector.set_mode(SmcMode::Report);
+        let hit = write_after_executing(&mut detector, &mut memory, 0x0400, 0xEA).expect("opcode write");
+        assert_eq!((hit.pc, hit.address, hit.old_value, hit.new_value, hit.operand), (0x0410, 0x0400, 0x00, 0xEA, false));
+        let hit = write_after_executing(&mut detector, &mut memory, 0x0402, 0x12).expect("operand write");
+        assert!(hit.operand);
+        assert!(write_after_executing(&mut detector, &mut memory, 0x0403, 0x12).is_none());
+    }
+
+    #[test]
+    fn ignores_unchanged_bytes_and_off_mode() {
+        let mut detector = SmcDetector::new();
+        let mut memory = vec![0u8; 0x10000];
+        assert!(write_after_executing(&mut detector, &mut memory, 0x0400, 0xEA).is_none());
+        detector.set_mode(SmcMode::Report);
+        assert!(write_after_executing(&mut detector, &mut memory, 0x0401, 0x00).is_none());
+    }
+
+    #[test]
+    fn remembers_code_executed_while_off() {
+        let mut detector = SmcDetector::new();
+        let mut memory = vec![0u8; 0x10000];
+        assert!(detector.update(0x0400, 3, &[], &memory).is_none());
+        detector.set_mode(SmcMode::Report);
+        memory[0x0401] = 0x12;
+        assert!(detector.update(0x0410, 3, &[(0x0401, 0x00)], &memory).expect("operand write").operand);
+        memory[0x0400] = 0xEA;
+        assert!(detector.update(0x0410, 3, &[(0x0400, 0x00)], &memory).is_some());
+        assert_eq!(detector.patched.take(), Some((0x0400, 0x0401)));
+    }
+
+    #[test]
+    fn report_mode_only_reports_a_site_once() {
+        let mut detector = SmcDetector::new();
+        let mut memory = vec![0u8; 0x10000];
+        detector.set_mode(SmcMode::Report);
+        assert!(write_after_executing(&mut detector, &mut memory, 0x0401, 1).is_some());
+        assert!(write_after_executing(&mut detector, &mut memory, 0x0401, 2).is_none());
+        detector.set_mode(SmcMode::Break);
+        assert!(write_after_executing(&mut detector, &mut memory, 0x0401, 3).is_some());
+        assert_eq!(detector.sites.get(&(0x0410, 0x0401)), Some(&3));
+        detector.reset();
+        assert!(detector.sites.is_empty());
+        assert!(detector.update(0x0410, 3, &[(0x0401, 3)], &memory).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+// Diagnoses misuse of the hardware stack: SP wrapping around the stack page, routines returning with a different SP
+// than they were entered with, and returns to addresses no call pushed. Some programs do these on purpose, e.g.
+// pushing an address and using RTS as a jump, so every instruction is only reported the first time unless breaking.
+
+use std::collections::BTreeMap;
+
+use crate::{cpu::Cpu, cpu_helpers::{CpuState, Instruction, Operation}, call_stack::{Frame, FrameKind}};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StackCheckMode {
+    Off,
+    Report,
+    Break,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StackIssueKind {
+    Overflow,                               // A push wrapped SP from $00 to $FF
+    Underflow,                              // A pull wrapped SP from $FF to $00
+    Unbalanced { routine: u16, entry_sp: u8 },  // The return ran with a different SP than the routine was entered with
+    UnpushedReturn { pushed: Option<u16> },     // Returned to an address no call pushed, `pushed` is what the innermost one did
+}
+
+#[derive(Clone, Copy)]
+pub struct StackIssue {
+    pub pc: u16,
+    pub operation: Operation,
+    pub sp_before: u8,
+    pub sp_after: u8,
+    pub pc_after: u16,
+    pub kind: StackIssueKind,
+}
+
+pub struct StackChecker {
+    pub mode: StackCheckMode,
+    pub sites: BTreeMap<u16, usize>,    // Issues by instruction address
+}
+
+impl StackChecker {
+    pub fn new() -> Self {
+        StackChecker { mode: StackCheckMode::Off, sites: BTreeMap::new() }
+    }
+
+    /// Checks `instruction`, executed from the state `before` inside the shadow stack `frames`, which ended in `cpu`.
+    /// Returns the issue to surface, if any.
+    pub fn check(&mut self, before: &CpuState, instruction: &Instruction, cpu: &Cpu, frames: &[Frame]) -> Option<StackIssue> {
+        if self.mode == StackCheckMode::Off {
+            return None;
+        }
+        let kind = match instruction.operation {
+            Operation::Pha | Operation::Php | Operation::Jsr | Operation::Brk if cpu.sp > before.sp => StackIssueKind::Overflow,
+            Operation::Pla | Operation::Plp | Operation::Rts | Operation::Rti if cpu.sp < before.sp => StackIssueKind::Underflow,
+            Operation::Rts | Operation::Rti => {
+                let kind = if instruction.operation == Operation::Rts { FrameKind::Subroutine } else { FrameKind::Interrupt };
+                match frames.last() {
+                    None => StackIssueKind::UnpushedReturn { pushed: None },
+                    Some(frame) if frame.sp_at_entry != before.sp => StackIssueKind::Unbalanced { routine: frame.target, entry_sp: frame.sp_at_entry },
+                    Some(frame) if frame.kind != kind || frame.return_address != cpu.pc => StackIssueKind::UnpushedReturn { pushed: Some(frame.return_address) },
+                    Some(_) => return None,
+                }
+            },
+            _ => return None,
+        };
+        let count = self.sites.entry(before.pc).or_insert(0);
+        *count += 1;
+        if *count > 1 && self.mode != StackCheckMode::Break {
+            return None;
+        }
+        Some(StackIssue { pc: before.pc, operation: instruction.operation, sp_before: before.sp, sp_after: cpu.sp, pc_after: cpu.pc, kind })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StackChecker, StackCheckMode, StackIssue, StackIssueKind};
+    use crate::{cpu::Cpu, cpu_helpers::{CpuState, Instruction, Operation}, call_stack::{Frame, FrameKind}};
+
+    const ROUTINE: Frame = Frame { kind: FrameKind::Subroutine, caller_pc: 0x0400, target: 0x0410, sp_at_entry: 0xFD, return_address: 0x0403 };
+
+    // Checks `operation` at $0420 taking SP from `sp_before` to `sp_after` and continuing at `pc_after`
+    fn check(checker: &mut StackChecker, operation: Operation, sp_before: u8, sp_after: u8, pc_after: u16, frames: &[Frame]) -> Option<StackIssue> {
+        let before = CpuState { pc: 0x0420, sp: sp_before, ..CpuState::new() };
+        let mut cpu = Cpu::new();
+        cpu.sp = sp_after;
+        cpu.pc = pc_after;
+        checker.check(&before, &Instruction { operation, ..Instruction::new() }, &cpu, frames)
+    }
+
+    fn kind(checker: &mut StackChecker, operation: Operation, sp_before: u8, sp_after: u8, pc_after: u16, frames: &[Frame]) -> Option<StackIssueKind> {
+        check(checker, operation, sp_before, sp_after, pc_after, frames).map(|issue| issue.kind)
+    }
+
+    #[test]
+    fn finds_sp_wrapping_around_the_stack_page() {
+        let mut checker = StackChecker::new();
+        checker.mode = StackCheckMode::Break;
+        assert!(kind(&mut checker, Operation::Pha, 0x00, 0xFF, 0x0421, &[]) == Some(StackIssueKind::Overflow));
+        assert!(kind(&mut checker, Operation::Jsr, 0x01, 0xFF, 0x0410, &[]) == Some(StackIssueKind::Overflow));
+        assert!(kind(&mut checker, Operation::Pla, 0xFF, 0x00, 0x0421, &[]) == Some(StackIssueKind::Underflow));
+        assert!(kind(&mut checker, Operation::Pha, 0x80, 0x7F, 0x0421, &[]).is_none());
+    }
+
+    #[test]
+    fn checks_returns_against_the_shadow_stack() {
+        let mut checker = StackChecker::new();
+        checker.mode = StackCheckMode::Break;
+        assert!(kind(&mut checker, Operation::Rts, 0xFD, 0xFF, 0x0403, &[ROUTINE]).is_none());
+        assert!(kind(&mut checker, Operation::Rts, 0xFB, 0xFD, 0x0403, &[ROUTINE]) == Some(StackIssueKind::Unbalanced { routine: 0x0410, entry_sp: 0xFD }));
+        assert!(kind(&mut checker, Operation::Rts, 0xFD, 0xFF, 0x0500, &[ROUTINE]) == Some(StackIssueKind::UnpushedReturn { pushed: Some(0x0403) }));
+        let interrupt = Frame { kind: FrameKind::Interrupt, sp_at_entry: 0xFC, ..ROUTINE };
+        assert!(kind(&mut checker, Operation::Rts, 0xFC, 0xFE, 0x0403, &[interrupt]) == Some(StackIssueKind::UnpushedReturn { pushed: Some(0x0403) }));
+        assert!(kind(&mut checker, Operation::Rts, 0xFD, 0xFF, 0x0403, &[]) == Some(StackIssueKind::UnpushedReturn { pushed: None }));
+    }
+
+    #[test]
+    fn report_mode_only_reports_an_instruction_once() {
+        let mut checker = StackChecker::new();
+        assert!(check(&mut checker, Operation::Pha, 0x00, 0xFF, 0x0421, &[]).is_none());
+        checker.mode = StackCheckMode::Report;
+        let issue = check(&mut checker, Operation::Pha, 0x00, 0xFF, 0x0421, &[]).expect("first overflow");
+        assert_eq!((issue.pc, issue.sp_before, issue.sp_after, issue.pc_after), (0x0420, 0x00, 0xFF, 0x0421));
+        assert!(check(&mut checker, Operation::Pha, 0x00, 0xFF, 0x0421, &[]).is_none());
+        checker.mode = StackCheckMode::Break;
+        assert!(check(&mut checker, Operation::Pha, 0x00, 0xFF, 0x0421, &[]).is_some());
+        assert_eq!(checker.sites.get(&0x0420), Some(&3));
+    }
+}
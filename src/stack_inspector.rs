@@ -0,0 +1,119 @@
+// Splits the used part of the hardware stack into return addresses, interrupt frames and pushed data.
+// Frames on the shadow call stack are certain, other return addresses are guessed from the JSR in front of them.
+
+use crate::{cpu::Cpu, call_stack::{Frame, FrameKind}, cpu_helpers::Operation};
+
+const STACK_PAGE: u16 = 0x100;
+const STACK_END: u16 = 0x1FF;
+
+pub enum StackEntryKind {
+    ReturnAddress { return_address: u16, call_site: u16, target: u16, guessed: bool },
+    Interrupt { status: u8, return_address: u16 },
+    Data,
+}
+
+pub struct StackEntry {
+    pub address: u16,   // Lowest address of the entry, the last byte pushed
+    pub bytes: Vec<u8>,
+    pub kind: StackEntryKind,
+}
+
+/// Entries from SP+1 up to $01FF, i.e. from the most recently pushed one
+pub fn inspect_stack(cpu: &Cpu, frames: &[Frame]) -> Vec<StackEntry> {
+    let mut entries = Vec::new();
+    let mut address = STACK_PAGE + cpu.sp as u16 + 1;
+    while address <= STACK_END {
+        let entry = frame_entry(cpu, frames, address).or_else(|| guessed_return_address(cpu, address))
+            .unwrap_or_else(|| StackEntry { address, bytes: vec![cpu.memory[address as usize]], kind: StackEntryKind::Data });
+        address += entry.bytes.len() as u16;
+        entries.push(entry);
+    }
+    entries
+}
+
+// A frame of the shadow call stack starting at `address`, if its bytes are still intact
+fn frame_entry(cpu: &Cpu, frames: &[Frame], address: u16) -> Option<StackEntry> {
+    let frame = frames.iter().rev().find(|frame| STACK_PAGE + frame.sp_at_entry as u16 + 1 == address)?;
+    let (length, kind) = match frame.kind {
+        // JSR pushes the address of its last byte, RTS adds one
+        FrameKind::Subroutine => (2, StackEntryKind::ReturnAddress { return_address: frame.return_address, call_site: frame.caller_pc, target: frame.target, guessed: false }),
+        FrameKind::Interrupt => (3, StackEntryKind::Interrupt { status: cpu.memory[address as usize], return_address: frame.return_address }),
+    };
+    if address + length - 1 > STACK_END {
+        return None;
+    }
+    let bytes = cpu.memory[address as usize..(address + length) as usize].to_vec();
+    let pushed = u16::from_le_bytes([bytes[length as usize - 2], bytes[length as usize - 1]]);
+    let expected = if frame.kind == FrameKind::Subroutine { frame.return_address.wrapping_sub(1) } else { frame.return_address };
+    if pushed != expected {
+        return None;
+    }
+    Some(StackEntry { address, bytes, kind })
+}
+
+// Two bytes that point at the last byte of a JSR are most likely its return address
+fn guessed_return_address(cpu: &Cpu, address: u16) -> Option<StackEntry> {
+    if address >= STACK_END {
+        return None;
+    }
+    let bytes = cpu.memory[address as usize..=address as usize + 1].to_vec();
+    let call_site = u16::from_le_bytes([bytes[0], bytes[1]]).wrapping_sub(2);
+    // The operand of a JSR at the very end of memory would wrap around
+    if call_site > 0xFFFD {
+        return None;
+    }
+    let instruction = cpu.get_instruction_at(call_site);
+    if instruction.operation != Operation::Jsr {
+        return None;
+    }
+    let kind = StackEntryKind::ReturnAddress { return_address: call_site.wrapping_add(3), call_site, target: instruction.value, guessed: true };
+    Some(StackEntry { address, bytes, kind })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu_runner::CpuRunner;
+    use super::{inspect_stack, StackEntryKind};
+
+    // 0400 JSR $0410, 0410 LDA #$42, 0412 PHA, 0413 JSR $0420, 0420 BRK to $0430
+    fn runner_in_interrupt() -> CpuRunner {
+        let mut runner = CpuRunner::new();
+        for (address, bytes) in [(0x0400, &[0x20, 0x10, 0x04][..]), (0x0410, &[0xA9, 0x42, 0x48, 0x20, 0x20, 0x04]), (0x0420, &[0x00]), (0xFFFE, &[0x30, 0x04])] {
+            runner.cpu.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        runner.cpu.pc = 0x0400;
+        for _i in 0..5 {
+            runner.step();
+        }
+        assert_eq!(runner.cpu.pc, 0x0430);
+        runner
+    }
+
+    fn describe(runner: &CpuRunner) -> Vec<String> {
+        inspect_stack(&runner.cpu, &runner.call_stack.frames).iter().map(|entry| {
+            let kind = match entry.kind {
+                StackEntryKind::ReturnAddress { return_address, call_site, target, guessed } => format!("return {:04X} from {:04X} to {:04X}{}", return_address, call_site, target, if guessed { "?" } else { "" }),
+                StackEntryKind::Interrupt { return_address, .. } => format!("interrupt {:04X}", return_address),
+                StackEntryKind::Data => "data".to_string(),
+            };
+            format!("{:04X} {}", entry.address, kind)
+        }).collect()
+    }
+
+    #[test]
+    fn frames_of_the_shadow_call_stack_are_annotated() {
+        let runner = runner_in_interrupt();
+        assert_eq!(describe(&runner), ["01F8 interrupt 0422", "01FB return 0416 from 0413 to 0420", "01FD data", "01FE return 0403 from 0400 to 0410"]);
+    }
+
+    #[test]
+    fn return_addresses_are_guessed_without_frames() {
+        let mut runner = runner_in_interrupt();
+        runner.call_stack.frames.clear();
+        assert_eq!(describe(&runner), ["01F8 data", "01F9 data", "01FA data", "01FB return 0416 from 0413 to 0420?", "01FD data", "01FE return 0403 from 0400 to 0410?"]);
+        // A frame whose bytes were overwritten is no longer trusted
+        let mut runner = runner_in_interrupt();
+        runner.cpu.memory[0x01FF] = 0x12;
+        assert_eq!(describe(&runner)[3..], ["01FE data", "01FF data"]);
+    }
+}
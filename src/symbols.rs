@@ -0,0 +1,223 @@
+// Label files for naming addresses. Supported formats:
+//   VICE:          al C:0400 .main_loop
+//   ca65/ld65:     sym	id=0,name="main_loop",addrsize=absolute,...,val=0x400,type=lab
+//   simple:        main_loop = $0400
+
+use std::{fmt, fs::read_to_string, collections::{HashMap, BTreeMap}};
+
+#[derive(Debug)]
+pub struct SymbolFileError {
+    details: String
+}
+
+impl SymbolFileError {
+    pub fn new(msg: &str) -> SymbolFileError {
+        SymbolFileError{details: msg.to_string()}
+    }
+}
+
+impl fmt::Display for SymbolFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}",self.details)
+    }
+}
+
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    by_address: BTreeMap<u16, String>,  // The first label loaded for an address is the one displayed
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable { by_name: HashMap::new(), by_address: BTreeMap::new() }
+    }
+
+    pub fn add(&mut self, name: &str, address: u16) {
+        self.by_name.insert(name.to_string(), address);
+        self.by_address.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(|name| name.as_str())
+    }
+
+    /// Labels defined in the given inclusive address range
+    pub fn names_in_range(&self, start: u16, end: u16) -> impl Iterator<Item = (&u16, &String)> {
+        self.by_address.range(start..=end)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.by_name.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    /// Loads a label file, detecting its format from the contents. Returns the amount of labels read.
+    pub fn load_file(&mut self, filename: &str) -> Result<usize, SymbolFileError> {
+        let contents = match read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(err) => return Err(SymbolFileError::new(&format!("Could not read {}: {}", filename, err))),
+        };
+        let first_line = contents.lines().map(|line| line.trim()).find(|line| !line.is_empty()).unwrap_or("");
+        let mut count = 0;
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            let parsed = if first_line.starts_with("al ") {
+                parse_vice_line(line)
+            }
+            else if first_line.starts_with("version") {
+                // Only sym lines are of interest here, everything else in a debug file is skipped
+                if !line.starts_with("sym") {
+                    continue;
+                }
+                match parse_dbg_symbol(line) {
+                    Some(symbol) => Some(symbol),
+                    None => continue,
+                }
+            }
+            else {
+                parse_simple_line(line)
+            };
+
+            match parsed {
+                Some((name, address)) => {
+                    self.add(&name, address);
+                    count += 1;
+                },
+                None => return Err(SymbolFileError::new(&format!("{}:{}: could not parse \"{}\"", filename, line_number + 1, line))),
+            }
+        }
+        Ok(count)
+    }
+}
+
+pub fn parse_hex_address(value: &str) -> Option<u16> {
+    let value = value.trim();
+    let digits = value.strip_prefix('$').or_else(|| value.strip_prefix("0x")).unwrap_or(value);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_vice_line(line: &str) -> Option<(String, u16)> {
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "al" {
+        return None;
+    }
+    let address = parts.next()?;
+    // The address may carry a memory space prefix, e.g. C:0400
+    let address = address.rsplit(':').next()?;
+    let name = parts.next()?.trim_start_matches('.');
+    Some((name.to_string(), parse_hex_address(address)?))
+}
+
+/// Splits the key=value list of a ca65 debug info line, leaving quoted values intact
+pub fn parse_dbg_fields(line: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let list = match line.split_once(char::is_whitespace) {
+        Some((_, list)) => list,
+        None => return fields,
+    };
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (index, c) in list.char_indices().chain(std::iter::once((list.len(), ','))) {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        else if c == ',' && !in_quotes {
+            if let Some((key, value)) = list[start..index].split_once('=') {
+                fields.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string());
+            }
+            start = index + 1;
+        }
+    }
+    fields
+}
+
+/// Returns None for anything but labels, e.g. equates or import entries
+fn parse_dbg_symbol(line: &str) -> Option<(String, u16)> {
+    let fields = parse_dbg_fields(line);
+    if fields.get("type").map(|t| t.as_str()) != Some("lab") {
+        return None;
+    }
+    Some((fields.get("name")?.clone(), parse_hex_address(fields.get("val")?)?))
+}
+
+fn parse_simple_line(line: &str) -> Option<(String, u16)> {
+    let (name, address) = line.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((name.to_string(), parse_hex_address(address)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{write, remove_file};
+
+    use super::{SymbolTable, parse_hex_address, parse_dbg_fields, parse_vice_line, parse_simple_line};
+
+    // Loads `contents` through a temporary file, as load_file only reads from disk
+    fn load(name: &str, contents: &str) -> (SymbolTable, Result<usize, String>) {
+        let filename = std::env::temp_dir().join(format!("symbols_test_{}_{}", std::process::id(), name));
+        write(&filename, contents).expect("temporary label file");
+        let mut symbols = SymbolTable::new();
+        let result = symbols.load_file(filename.to_str().unwrap()).map_err(|err| err.to_string());
+        remove_file(&filename).ok();
+        (symbols, result)
+    }
+
+    #[test]
+    fn hex_addresses_take_any_prefix() {
+        assert_eq!(parse_hex_address("$0400"), Some(0x0400));
+        assert_eq!(parse_hex_address(" 0xFFFC "), Some(0xFFFC));
+        assert_eq!(parse_hex_address("c000"), Some(0xC000));
+        assert_eq!(parse_hex_address("10000"), None);
+        assert_eq!(parse_hex_address("main"), None);
+    }
+
+    #[test]
+    fn lines_of_each_format() {
+        assert_eq!(parse_vice_line("al C:0400 .main_loop"), Some(("main_loop".to_string(), 0x0400)));
+        assert_eq!(parse_vice_line("al 0410 sub"), Some(("sub".to_string(), 0x0410)));
+        assert_eq!(parse_vice_line("xx C:0400 .main"), None);
+        assert_eq!(parse_simple_line("reset = $FFFC"), Some(("reset".to_string(), 0xFFFC)));
+        assert_eq!(parse_simple_line("two words = $0400"), None);
+        let fields = parse_dbg_fields("sym\tid=0,name=\"a,b\",val=0x400,type=lab");
+        assert_eq!(fields.get("name").map(String::as_str), Some("a,b"));
+        assert_eq!(fields.get("val").map(String::as_str), Some("0x400"));
+    }
+
+    #[test]
+    fn files_are_detected_by_their_first_line() {
+        let (symbols, result) = load("vice", "al C:0400 .start\nal C:0410 .sub\n");
+        assert_eq!(result, Ok(2));
+        assert_eq!(symbols.address_of("sub"), Some(0x0410));
+
+        let dbg = "version\tmajor=2,minor=0\nfile\tid=0,name=\"a.s\"\nsym\tid=0,name=\"sub\",val=0x410,type=lab\nsym\tid=1,name=\"CONST\",val=0x3,type=equ\n";
+        let (symbols, result) = load("dbg", dbg);
+        assert_eq!(result, Ok(1));
+        assert_eq!((symbols.name_at(0x0410), symbols.address_of("CONST")), (Some("sub"), None));
+
+        let (symbols, result) = load("simple", "; comment\nstart = $0400\n\nalias = $0400\n");
+        assert_eq!(result, Ok(2));
+        assert_eq!((symbols.name_at(0x0400), symbols.address_of("alias"), symbols.len()), (Some("start"), Some(0x0400), 2));
+        assert_eq!(symbols.names_in_range(0x0000, 0x03FF).count(), 0);
+    }
+
+    #[test]
+    fn bad_lines_name_their_position() {
+        let (_, result) = load("bad", "start = $0400\nnot a label\n");
+        assert!(result.is_err_and(|err| err.ends_with(":2: could not parse \"not a label\"")));
+        let (_, result) = load("empty", "");
+        assert_eq!(result, Ok(0));
+    }
+}
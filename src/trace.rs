@@ -0,0 +1,179 @@
+// Instruction trace formats and a tool to find where two traces diverge.
+// The nestest format matches the nestest.log that comes with the nestest ROM, so a trace can be diffed against it directly.
+
+use std::fs::read_to_string;
+
+use crate::{cpu::Cpu, cpu_helpers::{AddressMode, Operation, FLAG_BREAK, FLAG_UNUSED}};
+
+const DEFAULT_CONTEXT: usize = 5;
+const DOTS_PER_SCANLINE: u64 = 341;
+const SCANLINES_PER_FRAME: u64 = 262;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Default,
+    Nestest,
+}
+
+/// Formats the instruction at the PC like nestest.log, with the registers from before it runs, e.g.
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+pub fn nestest_line(cpu: &Cpu) -> String {
+    let pc = cpu.pc;
+    let size = cpu.get_instruction_at(pc).address_mode.address_size() as u16 + 1;
+    let bytes: Vec<String> = (0..size).map(|offset| format!("{:02X}", cpu.memory[pc.wrapping_add(offset) as usize])).collect();
+    // The PPU runs three dots per cpu cycle
+    let dots = cpu.cycles * 3;
+    // B only exists in pushed copies of the status register, nestest.log shows the register without it
+    let status = (cpu.sr & !FLAG_BREAK) | FLAG_UNUSED;
+    format!("{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc, bytes.join(" "), nestest_disassembly(cpu, pc), cpu.a, cpu.x, cpu.y, status, cpu.sp,
+        (dots / DOTS_PER_SCANLINE) % SCANLINES_PER_FRAME, dots % DOTS_PER_SCANLINE, cpu.cycles)
+}
+
+// nestest annotates operands with the address they resolve to and the value stored there before the instruction runs
+fn nestest_disassembly(cpu: &Cpu, pc: u16) -> String {
+    let instruction = cpu.get_instruction_at(pc);
+    let mnemonic = instruction.operation.as_ref().to_uppercase();
+    let value = instruction.value;
+    let peek = |address: u16| cpu.memory[address as usize];
+    // Pointers in the zero page wrap around within it
+    let zero_page_pointer = |address: u8| u16::from_le_bytes([peek(address as u16), peek(address.wrapping_add(1) as u16)]);
+    let operand = match instruction.address_mode {
+        AddressMode::Imp | AddressMode::Inv => return mnemonic,
+        AddressMode::Acc => "A".to_string(),
+        AddressMode::Imm => format!("#${:02X}", value),
+        AddressMode::Zpg => format!("${:02X} = {:02X}", value, peek(value)),
+        AddressMode::Zpx | AddressMode::Zpy => {
+            let (register, index) = if instruction.address_mode == AddressMode::Zpx { ('X', cpu.x) } else { ('Y', cpu.y) };
+            let address = (value as u8).wrapping_add(index) as u16;
+            format!("${:02X},{} @ {:02X} = {:02X}", value, register, address, peek(address))
+        },
+        AddressMode::Abs => match instruction.operation {
+            Operation::Jmp | Operation::Jsr => format!("${:04X}", value),
+            _ => format!("${:04X} = {:02X}", value, peek(value)),
+        },
+        AddressMode::Abx | AddressMode::Aby => {
+            let (register, index) = if instruction.address_mode == AddressMode::Abx { ('X', cpu.x) } else { ('Y', cpu.y) };
+            let address = value.wrapping_add(index as u16);
+            format!("${:04X},{} @ {:04X} = {:02X}", value, register, address, peek(address))
+        },
+        AddressMode::Ind => format!("(${:04X}) = {:04X}", value, cpu.read_memory_u16(value)),
+        AddressMode::Inx => {
+            let pointer = (value as u8).wrapping_add(cpu.x);
+            let address = zero_page_pointer(pointer);
+            format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", value, pointer, address, peek(address))
+        },
+        AddressMode::Iny => {
+            let base = zero_page_pointer(value as u8);
+            let address = base.wrapping_add(cpu.y as u16);
+            format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", value, base, address, peek(address))
+        },
+        AddressMode::Rel => format!("${:04X}", pc.wrapping_add(2).wrapping_add(value as u8 as i8 as u16)),
+    };
+    format!("{} {}", mnemonic, operand)
+}
+
+/// Compares two trace files line by line and prints the first divergence, with up to `context` preceding lines.
+/// Trailing whitespace is ignored, so traces with Windows line endings compare equal.
+pub fn print_trace_diff(first: &str, second: &str, context: Option<usize>) {
+    let (first_trace, second_trace) = match (read_to_string(first), read_to_string(second)) {
+        (Ok(first_trace), Ok(second_trace)) => (first_trace, second_trace),
+        (Err(err), _) => return println!("Could not read {}: {}", first, err),
+        (_, Err(err)) => return println!("Could not read {}: {}", second, err),
+    };
+    let first_lines: Vec<&str> = first_trace.lines().map(str::trim_end).collect();
+    let second_lines: Vec<&str> = second_trace.lines().map(str::trim_end).collect();
+    let report = trace_diff(&first_lines, &second_lines, context.unwrap_or(DEFAULT_CONTEXT));
+    for line in &report {
+        println!("{}", line);
+    }
+    if report.len() > 1 {
+        println!("- is {}, + is {}", first, second);
+    }
+}
+
+// The report for the first divergence, where - lines come from the first trace and + lines from the second
+fn trace_diff(first_lines: &[&str], second_lines: &[&str], context: usize) -> Vec<String> {
+    let common = first_lines.len().min(second_lines.len());
+    let divergence = (0..common).find(|&index| first_lines[index] != second_lines[index]);
+    let index = match divergence {
+        Some(index) => index,
+        None if first_lines.len() == second_lines.len() => return vec![format!("Traces match, {} lines", common)],
+        None => common,
+    };
+
+    let width = (index + 1).to_string().len();
+    let mut report = vec![format!("Traces diverge at line {}", index + 1)];
+    for (number, line) in first_lines.iter().enumerate().take(index).skip(index.saturating_sub(context)) {
+        report.push(format!("  {:>width$}  {}", number + 1, line, width = width));
+    }
+    let first_line = first_lines.get(index).copied();
+    let second_line = second_lines.get(index).copied();
+    report.push(format!("- {:>width$}  {}", index + 1, first_line.unwrap_or("<end of trace>"), width = width));
+    report.push(format!("+ {:>width$}  {}", index + 1, second_line.unwrap_or("<end of trace>"), width = width));
+    if let (Some(first_line), Some(second_line)) = (first_line, second_line) {
+        // Point at the first differing character, and name the register column it is in
+        let column = first_line.chars().zip(second_line.chars()).take_while(|(a, b)| a == b).count();
+        let field = first_line.char_indices().take_while(|(position, _)| *position <= column)
+            .filter(|(_, c)| *c == ':').last()
+            .and_then(|(position, _)| first_line[..position].rsplit(' ').next())
+            .map(|name| format!(" {} differs", name))
+            .unwrap_or_default();
+        report.push(format!("  {:>width$}  {}^{}", "", " ".repeat(column), field, width = width));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use super::{nestest_line, trace_diff};
+
+    fn cpu_at(pc: u16, bytes: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.memory[pc as usize..pc as usize + bytes.len()].copy_from_slice(bytes);
+        cpu.pc = pc;
+        cpu.sr = 0x24;
+        cpu.sp = 0xFD;
+        cpu
+    }
+
+    #[test]
+    fn lines_match_nestest_log() {
+        let mut cpu = cpu_at(0xC000, &[0x4C, 0xF5, 0xC5]);
+        cpu.cycles = 7;
+        assert_eq!(nestest_line(&cpu), "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        cpu.execute_next_instruction();
+        cpu.memory[0xC5F5..0xC5F7].copy_from_slice(&[0xA2, 0x00]);
+        assert_eq!(nestest_line(&cpu), "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10");
+    }
+
+    #[test]
+    fn operands_show_the_resolved_address_and_value() {
+        // The pointer at $FF wraps around to $00 for its high byte
+        let mut cpu = cpu_at(0x0400, &[0xB1, 0xFF]);
+        cpu.memory[0x00FF] = 0x00;
+        cpu.memory[0x0000] = 0x03;
+        cpu.memory[0x0302] = 0x89;
+        cpu.y = 0x02;
+        cpu.sr = 0x34;
+        cpu.cycles = 341;
+        assert_eq!(nestest_line(&cpu), "0400  B1 FF     LDA ($FF),Y = 0300 @ 0302 = 89  A:00 X:00 Y:02 P:24 SP:FD PPU:  3,  0 CYC:341");
+    }
+
+    #[test]
+    fn diff_reports_the_first_divergence() {
+        let first = ["C000  A:00 X:00", "C002  A:00 X:01", "C004  A:00 X:02", "C006  A:05 X:03"];
+        let second = ["C000  A:00 X:00", "C002  A:00 X:01", "C004  A:00 X:02", "C006  A:05 X:04"];
+        assert_eq!(trace_diff(&first, &second, 2), [
+            "Traces diverge at line 4",
+            "  2  C002  A:00 X:01",
+            "  3  C004  A:00 X:02",
+            "- 4  C006  A:05 X:03",
+            "+ 4  C006  A:05 X:04",
+            "                   ^ X differs",
+        ]);
+        assert_eq!(trace_diff(&first, &first, 2), ["Traces match, 4 lines"]);
+        assert_eq!(trace_diff(&first, &second[..2], 0), ["Traces diverge at line 3", "- 3  C004  A:00 X:02", "+ 3  <end of trace>"]);
+    }
+}
@@ -0,0 +1,402 @@
+// Full screen terminal front end, with live panes for the registers, disassembly, memory, stack, breakpoints and history.
+// Values that changed since execution last resumed are highlighted. Only built with the tui feature.
+
+use std::{io, time::{Duration, Instant}};
+
+use ratatui::{DefaultTerminal, Frame, layout::{Constraint, Layout, Rect}, style::{Color, Modifier, Style}, text::{Line, Span}, widgets::{Block, Paragraph}, crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers}};
+
+use crate::{cpu_runner::{CpuRunner, StepTarget}, cpu_helpers::{CpuState, Operation}, breakpoint::BreakpointKind, stack_inspector::{inspect_stack, StackEntryKind}, smc_detector::SmcMode, stack_checker::StackCheckMode};
+
+const FRAME_TIME: Duration = Duration::from_millis(50);    // How long to run between redraws
+const STEPS_PER_CHECK: usize = 1000;
+const MEMORY_ROWS: u16 = 8;
+const DISASSEMBLY_CONTEXT: i64 = 4;     // Instructions shown before the PC
+const FLAG_NAMES: &str = "NV-BDIZC";
+const HELP: &str = "s step  o over  f finish  c continue  esc pause  r back  b breakpoint  g memory  pgup/pgdn scroll  q quit";
+
+pub struct Tui<'a> {
+    runner: &'a mut CpuRunner,
+    running: bool,
+    status: String,
+    memory_start: u16,
+    previous_state: CpuState,   // As of the last time execution resumed
+    previous_memory: Vec<u8>,
+    input: Option<String>,      // Address being typed for the memory pane
+    finding: Option<String>,    // Last finding reported since execution resumed
+    quit: bool,
+}
+
+/// Takes over the terminal until the user quits
+pub fn run(runner: &mut CpuRunner) -> io::Result<()> {
+    // Keeps step from printing call stack warnings over the screen
+    runner.continuous_run = true;
+    let previous_state = runner.cpu.get_cpu_state();
+    let previous_memory = runner.cpu.memory.to_vec();
+    let memory_start = runner.cpu.pc & 0xFFF0;
+    let mut tui = Tui { runner, running: false, status: "Stopped".to_string(), memory_start, previous_state, previous_memory, input: None, finding: None, quit: false };
+    let mut terminal = ratatui::init();
+    let result = tui.event_loop(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl Tui<'_> {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if self.running {
+                self.run_slice();
+                while event::poll(Duration::ZERO)? {
+                    self.handle_event(event::read()?);
+                }
+            }
+            else {
+                self.handle_event(event::read()?);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs for a frame, stopping early like the prompt does at breakpoints, watchpoints, loops and step targets
+    fn run_slice(&mut self) {
+        let deadline = Instant::now() + FRAME_TIME;
+        while Instant::now() < deadline {
+            for _i in 0..STEPS_PER_CHECK {
+                self.runner.step();
+                if let Some(finding) = self.reported_finding() {
+                    self.finding = Some(finding);
+                }
+                if let Some(reason) = self.stop_reason() {
+                    self.running = false;
+                    self.runner.step_target = None;
+                    self.status = reason;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Report mode only shows the last finding on the status line, break mode stops on it in stop_reason
+    fn reported_finding(&mut self) -> Option<String> {
+        let runner = &mut *self.runner;
+        let hit = runner.smc_hit.take_if(|_| runner.smc_detector.mode == SmcMode::Report).map(|hit| runner.describe_smc_hit(&hit));
+        let issue = runner.stack_issue.take_if(|_| runner.stack_checker.mode == StackCheckMode::Report).map(|issue| runner.describe_stack_issue(&issue));
+        issue.or(hit)
+    }
+
+    fn stop_reason(&mut self) -> Option<String> {
+        let runner = &mut *self.runner;
+        if let Some(hit) = runner.watch_hit.take() {
+            Some(format!("Hit watchpoint {} at {}", hit.id, runner.format_address(hit.address)))
+        }
+        else if let Some(hit) = runner.smc_hit.take() {
+            Some(runner.describe_smc_hit(&hit))
+        }
+        else if let Some(issue) = runner.stack_issue.take() {
+            Some(runner.describe_stack_issue(&issue))
+        }
+        else if let Some(bp) = runner.breakpoints.hit(runner.cpu.pc) {
+            Some(format!("Hit breakpoint {} at {}{}", bp.id, runner.format_address(bp.address), bp.message.map(|message| format!(": {}", message)).unwrap_or_default()))
+        }
+        else if runner.reached_step_target() {
+            Some("Stopped".to_string())
+        }
+        else {
+            runner.loop_hit.take().map(|hit| runner.describe_loop_hit(&hit))
+        }
+    }
+
+    fn resume(&mut self, target: Option<StepTarget>) {
+        self.remember_state();
+        self.runner.step_target = target;
+        self.running = true;
+        self.status = "Running".to_string();
+        self.finding = None;
+    }
+
+    fn remember_state(&mut self) {
+        self.previous_state = self.runner.cpu.get_cpu_state();
+        self.previous_memory.copy_from_slice(&self.runner.cpu.memory);
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let key = match event {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => return,
+        };
+        if self.input.is_some() {
+            self.handle_input_key(key);
+            return;
+        }
+        let cpu = &self.runner.cpu;
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc if self.running => {
+                self.running = false;
+                self.runner.step_target = None;
+                self.status = format!("Paused at {}", self.runner.format_address(self.runner.cpu.pc));
+            },
+            _ if self.running => {},
+            KeyCode::Char('s') | KeyCode::F(11) if !key.modifiers.contains(KeyModifiers::SHIFT) => self.resume(Some(StepTarget::Steps(0))),
+            KeyCode::Char('f') | KeyCode::F(11) => self.resume(Some(self.runner.finish_target())),
+            KeyCode::Char('o') | KeyCode::F(10) => {
+                let target = if cpu.get_next_instruction().operation == Operation::Jsr {
+                    StepTarget::Over { return_pc: cpu.pc.wrapping_add(3), sp: cpu.sp }
+                }
+                else {
+                    StepTarget::Steps(0)
+                };
+                self.resume(Some(target));
+            },
+            KeyCode::Char('c') | KeyCode::F(5) => self.resume(None),
+            KeyCode::Char('r') => {
+                self.remember_state();
+                self.status = if self.runner.step_back() { "Stepped back".to_string() } else { "Reached the start of the recorded history".to_string() };
+            },
+            KeyCode::Char('b') => self.toggle_breakpoint(),
+            KeyCode::Char('g') => self.input = Some(String::new()),
+            KeyCode::Up => self.memory_start = self.memory_start.wrapping_sub(16),
+            KeyCode::Down => self.memory_start = self.memory_start.wrapping_add(16),
+            KeyCode::PageUp => self.memory_start = self.memory_start.wrapping_sub(MEMORY_ROWS * 16),
+            KeyCode::PageDown => self.memory_start = self.memory_start.wrapping_add(MEMORY_ROWS * 16),
+            _ => {},
+        }
+    }
+
+    // Typing an address expression for the memory pane
+    fn handle_input_key(&mut self, key: KeyEvent) {
+        let input = self.input.as_mut().expect("only called while typing");
+        match key.code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            },
+            KeyCode::Esc => self.input = None,
+            KeyCode::Enter => {
+                let text = self.input.take().unwrap_or_default();
+                match self.runner.evaluate(&text, 16).ok().and_then(|value| u16::try_from(value).ok()) {
+                    Some(address) => self.memory_start = address & 0xFFF0,
+                    None => self.status = format!("Invalid address {}", text),
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        let pc = self.runner.cpu.pc;
+        let existing = self.runner.breakpoints.iter().find(|bp| bp.kind == BreakpointKind::Execute && bp.address == pc).map(|bp| bp.id);
+        match existing {
+            Some(id) => {
+                self.runner.breakpoints.remove(id);
+                self.status = format!("Deleted breakpoint {}", id);
+            },
+            None => {
+                let id = self.runner.breakpoints.add(pc, None, false);
+                self.status = format!("Breakpoint {} at {}", id, self.runner.format_address(pc));
+            },
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, memory, status] = Layout::vertical([Constraint::Min(10), Constraint::Length(MEMORY_ROWS + 2), Constraint::Length(1)]).areas(frame.area());
+        let [left, disassembly, right] = Layout::horizontal([Constraint::Length(34), Constraint::Min(30), Constraint::Length(48)]).areas(main);
+        let [registers, stack] = Layout::vertical([Constraint::Length(7), Constraint::Min(3)]).areas(left);
+        let [breakpoints, history] = Layout::vertical([Constraint::Length(8), Constraint::Min(3)]).areas(right);
+
+        frame.render_widget(Paragraph::new(self.register_lines()).block(Block::bordered().title(" Registers ")), registers);
+        frame.render_widget(Paragraph::new(self.stack_lines()).block(Block::bordered().title(" Stack ")), stack);
+        frame.render_widget(Paragraph::new(self.disassembly_lines(disassembly)).block(Block::bordered().title(" Disassembly ")), disassembly);
+        frame.render_widget(Paragraph::new(self.breakpoint_lines()).block(Block::bordered().title(" Breakpoints ")), breakpoints);
+        frame.render_widget(Paragraph::new(self.history_lines(history)).block(Block::bordered().title(" History ")), history);
+        frame.render_widget(Paragraph::new(self.memory_lines()).block(Block::bordered().title(" Memory ")), memory);
+
+        let status_line = match &self.input {
+            Some(input) => format!("Memory address: {}", input),
+            None => match &self.finding {
+                Some(finding) => format!("{} | {} | {}", self.status, finding, HELP),
+                None => format!("{} | {}", self.status, HELP),
+            },
+        };
+        frame.render_widget(Paragraph::new(status_line).style(Style::new().add_modifier(Modifier::REVERSED)), status);
+    }
+
+    fn register_lines(&self) -> Vec<Line<'static>> {
+        let cpu = &self.runner.cpu;
+        let previous = &self.previous_state;
+        let register = |name: &str, value: String, changed: bool| vec![Span::raw(format!("{:<3}", name)), Span::styled(value, changed_style(changed)), Span::raw("  ")];
+
+        let mut flags = vec![Span::raw("   ")];
+        for (bit, name) in FLAG_NAMES.chars().enumerate() {
+            let mask = 0x80 >> bit;
+            let style = if cpu.sr & mask != 0 { Style::new().fg(Color::Green).add_modifier(Modifier::BOLD) } else { Style::new().fg(Color::DarkGray) };
+            let style = if (cpu.sr ^ previous.sr) & mask != 0 { style.bg(Color::Yellow) } else { style };
+            flags.push(Span::styled(name.to_string(), style));
+        }
+        vec![
+            Line::from(register("PC", self.runner.format_address(cpu.pc), cpu.pc != previous.pc)),
+            Line::from([register("A", format!("{:02X}", cpu.a), cpu.a != previous.a), register("X", format!("{:02X}", cpu.x), cpu.x != previous.x), register("Y", format!("{:02X}", cpu.y), cpu.y != previous.y)].concat()),
+            Line::from([register("SP", format!("{:02X}", cpu.sp), cpu.sp != previous.sp), register("SR", format!("{:02X}", cpu.sr), cpu.sr != previous.sr)].concat()),
+            Line::from(flags),
+            Line::from(format!("Cycles {}  Ops {}", cpu.cycles, self.runner.op_count)),
+        ]
+    }
+
+    fn stack_lines(&self) -> Vec<Line<'static>> {
+        inspect_stack(&self.runner.cpu, &self.runner.call_stack.frames).iter().map(|entry| {
+            let bytes: Vec<String> = entry.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let meaning = match entry.kind {
+                StackEntryKind::ReturnAddress { return_address, guessed, .. } => format!("{}ret {}", if guessed { "?" } else { "" }, self.runner.format_address(return_address)),
+                StackEntryKind::Interrupt { return_address, .. } => format!("irq {}", self.runner.format_address(return_address)),
+                StackEntryKind::Data => String::new(),
+            };
+            Line::from(format!("{:04X} {:<8} {}", entry.address, bytes.join(" "), meaning))
+        }).collect()
+    }
+
+    // The instructions from the PC on, with breakpoints marked
+    fn disassembly_lines(&self, area: Rect) -> Vec<Line<'static>> {
+        let cpu = &self.runner.cpu;
+        let mut lines = Vec::new();
+        // Without a run of instructions that ends right at the PC, the listing starts at it instead
+        let start = self.runner.instruction_start(cpu.pc, -DISASSEMBLY_CONTEXT);
+        let mut address = if self.runner.instruction_start(start, DISASSEMBLY_CONTEXT) == cpu.pc { start } else { cpu.pc };
+        for _i in 0..area.height.saturating_sub(2) {
+            let instruction = cpu.get_instruction_at(address);
+            let breakpoint = self.runner.breakpoints.iter().any(|bp| bp.enabled && bp.kind == BreakpointKind::Execute && bp.address == address);
+            let marker = match (address == cpu.pc, breakpoint) {
+                (true, true) => ">*",
+                (true, false) => "> ",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            let text = format!("{}{}", marker, self.runner.disassemble(&instruction, address));
+            let style = if address == cpu.pc { Style::new().add_modifier(Modifier::REVERSED) } else if breakpoint { Style::new().fg(Color::Red) } else { Style::new() };
+            lines.push(Line::styled(text, style));
+            address = address.wrapping_add(instruction.address_mode.address_size() as u16 + 1);
+        }
+        lines
+    }
+
+    fn breakpoint_lines(&self) -> Vec<Line<'static>> {
+        self.runner.breakpoints.iter().map(|bp| {
+            let style = if bp.enabled { Style::new() } else { Style::new().fg(Color::DarkGray) };
+            Line::styled(format!("{} {}", bp, self.runner.symbols.name_at(bp.address).unwrap_or("")), style)
+        }).collect()
+    }
+
+    // The most recently executed instructions, the newest at the bottom
+    fn history_lines(&self, area: Rect) -> Vec<Line<'static>> {
+        let runner = &self.runner;
+        let count = (area.height.saturating_sub(2) as usize).min(runner.op_count).min(runner.history_depth);
+        (1..=count).rev().map(|age| {
+            let (instruction, state) = runner.history_entry(age);
+            Line::from(format!("{:<28} A:{:02X} X:{:02X} Y:{:02X}", runner.disassemble(instruction, state.pc), state.a, state.x, state.y))
+        }).collect()
+    }
+
+    fn memory_lines(&self) -> Vec<Line<'static>> {
+        let memory = &self.runner.cpu.memory;
+        (0..MEMORY_ROWS).map(|row| {
+            let row_start = self.memory_start.wrapping_add(row * 16);
+            let mut spans = vec![Span::raw(format!("{:04X}: ", row_start))];
+            let mut text = String::new();
+            for offset in 0..16 {
+                let address = row_start.wrapping_add(offset) as usize;
+                let byte = memory[address];
+                spans.push(Span::styled(format!("{:02X}", byte), changed_style(byte != self.previous_memory[address])));
+                spans.push(Span::raw(" "));
+                text.push(if (0x20..0x7F).contains(&byte) { byte as char } else { '.' });
+            }
+            spans.push(Span::raw(format!("|{}|", text)));
+            Line::from(spans)
+        }).collect()
+    }
+}
+
+fn changed_style(changed: bool) -> Style {
+    if changed { Style::new().fg(Color::Black).bg(Color::Yellow) } else { Style::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{layout::Rect, crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers}};
+
+    use crate::cpu_runner::CpuRunner;
+    use super::Tui;
+
+    // 0400 JSR $0410, 0403 NOP, 0404 JMP $0404, 0410 INX, 0411 RTS
+    fn runner_with_call() -> CpuRunner {
+        let mut runner = CpuRunner::new();
+        for (address, bytes) in [(0x0400, &[0x20, 0x10, 0x04, 0xEA, 0x4C, 0x04, 0x04][..]), (0x0410, &[0xE8, 0x60])] {
+            runner.cpu.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        runner.cpu.pc = 0x0400;
+        runner.continuous_run = true;
+        runner
+    }
+
+    fn tui(runner: &mut CpuRunner) -> Tui<'_> {
+        let previous_state = runner.cpu.get_cpu_state();
+        let previous_memory = runner.cpu.memory.to_vec();
+        Tui { runner, running: false, status: "Stopped".to_string(), memory_start: 0x0400, previous_state, previous_memory, input: None, finding: None, quit: false }
+    }
+
+    // Presses the keys, running like the event loop does after each
+    fn press(tui: &mut Tui, keys: &[KeyCode]) {
+        for key in keys {
+            tui.handle_event(Event::Key(KeyEvent::new(*key, KeyModifiers::NONE)));
+            if tui.running {
+                tui.run_slice();
+            }
+        }
+    }
+
+    #[test]
+    fn keys_step_and_run_to_breakpoints() {
+        let mut runner = runner_with_call();
+        let mut tui = tui(&mut runner);
+        press(&mut tui, &[KeyCode::Char('s')]);
+        assert_eq!(tui.runner.cpu.pc, 0x0410);
+        press(&mut tui, &[KeyCode::Char('f')]);
+        assert_eq!((tui.runner.cpu.pc, tui.runner.cpu.x), (0x0403, 1));
+        press(&mut tui, &[KeyCode::Char('r'), KeyCode::Char('r'), KeyCode::Char('r')]);
+        assert_eq!((tui.runner.cpu.pc, tui.runner.cpu.x), (0x0400, 0));
+        press(&mut tui, &[KeyCode::Char('o')]);
+        assert_eq!((tui.runner.cpu.pc, tui.runner.cpu.x), (0x0403, 1));
+
+        press(&mut tui, &[KeyCode::Char('b')]);
+        assert_eq!(tui.status, "Breakpoint 1 at 0403");
+        press(&mut tui, &[KeyCode::Char('r'), KeyCode::Char('c')]);
+        assert_eq!((tui.runner.cpu.pc, tui.status.as_str()), (0x0403, "Hit breakpoint 1 at 0403"));
+        press(&mut tui, &[KeyCode::Char('b'), KeyCode::Char('q')]);
+        assert!(tui.runner.breakpoints.is_empty() && tui.quit);
+    }
+
+    #[test]
+    fn memory_pane_jumps_to_typed_addresses() {
+        let mut runner = runner_with_call();
+        let mut tui = tui(&mut runner);
+        press(&mut tui, &[KeyCode::Char('g'), KeyCode::Char('p'), KeyCode::Char('c'), KeyCode::Char('+'), KeyCode::Char('2'), KeyCode::Char('3'), KeyCode::Enter]);
+        assert_eq!(tui.memory_start, 0x0420);
+        press(&mut tui, &[KeyCode::Char('g'), KeyCode::Char('x'), KeyCode::Char('y'), KeyCode::Backspace, KeyCode::Backspace, KeyCode::Char('('), KeyCode::Enter]);
+        assert_eq!((tui.memory_start, tui.status.as_str()), (0x0420, "Invalid address ("));
+        press(&mut tui, &[KeyCode::PageDown, KeyCode::Up]);
+        assert_eq!(tui.memory_start, 0x0490);
+    }
+
+    #[test]
+    fn disassembly_marks_the_pc_and_breakpoints() {
+        let mut runner = runner_with_call();
+        runner.cpu.pc = 0x0403;
+        runner.breakpoints.add(0x0404, None, false);
+        let tui = tui(&mut runner);
+        let lines: Vec<String> = tui.disassembly_lines(Rect::new(0, 0, 40, 8)).iter().map(|line| line.to_string()).collect();
+        // A few instructions before the PC, then the PC and what follows it
+        assert_eq!(lines.len(), 6);
+        assert!(lines[3].starts_with("  ") && lines[3].contains("JSR"), "{}", lines[3]);
+        assert!(lines[4].starts_with("> ") && lines[4].contains("NOP"), "{}", lines[4]);
+        assert!(lines[5].starts_with(" *") && lines[5].contains("JMP"), "{}", lines[5]);
+    }
+}